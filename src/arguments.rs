@@ -1,6 +1,13 @@
 use std::path::PathBuf;
 
-use clap::{ArgGroup, Args, Parser, Subcommand};
+use clap::error::ErrorKind;
+use clap::{ArgGroup, Args, CommandFactory, Parser, Subcommand};
+
+use crate::crypt::Precision;
+use crate::image::comparison::Metric;
+use crate::image::dithering::Dither;
+use crate::image::metadata::MetadataPolicy;
+use crate::image::pixel_operations::Blend;
 
 #[derive(Debug, Parser)]
 #[command(author, version, about)]
@@ -11,6 +18,49 @@ pub struct Arguments {
     /// The server address
     #[arg(short, long, default_value = "127.0.0.1:34347")]
     pub address: String,
+    /// Store results as encrypted image files instead of decrypting them
+    #[arg(long)]
+    pub keep_encrypted: bool,
+    /// Replace the image stored on the server with the result instead of sending it back
+    #[arg(long, global = true)]
+    pub in_place: bool,
+    /// The name of the slot on the server holding the image to use, instead of the default one
+    #[arg(long, global = true)]
+    pub slot: Option<String>,
+    /// Store the result in the named slot on the server instead of sending it back
+    #[arg(long, global = true, conflicts_with = "in_place")]
+    pub store_as: Option<String>,
+    /// Run the operation as a job on the server and exit right away, printing the job's ID
+    #[arg(long, global = true)]
+    pub detach: bool,
+    /// What to do with image metadata like colour profiles when encrypting
+    #[arg(long, value_enum, default_value_t)]
+    pub metadata: MetadataPolicy,
+    /// The number of fractional bits of weights in operations like rescaling, more are more accurate
+    /// but slower
+    #[arg(long, default_value_t = 8, value_parser = clap::value_parser!(u32).range(1..=Precision::MAX_BITS as i64))]
+    pub precision: u32,
+}
+
+impl Arguments {
+    /// Parse the arguments of the process, exiting with a usage error if they do not fit together.
+    pub fn parse_checked() -> Self {
+        Self::parse().check().unwrap_or_else(|error| error.exit())
+    }
+
+    /// Reject the flags for storing results on the server for commands whose results are not
+    /// images, which clap cannot express for global flags.
+    fn check(self) -> Result<Self, clap::Error> {
+        let stores_result = self.in_place || self.store_as.is_some();
+        if stores_result && matches!(self.command, Command::Compare(_) | Command::Match(_)) {
+            return Err(Self::command().error(
+                ErrorKind::ArgumentConflict,
+                "--in-place and --store-as cannot be used with compare or match, whose results are not images",
+            ));
+        }
+
+        Ok(self)
+    }
 }
 
 #[derive(Debug, Subcommand)]
@@ -21,7 +71,11 @@ pub enum Command {
     Ping,
     /// Tell the server to shut down
     Shutdown,
-    /// Send an image to the server
+    /// Encrypt an image to an encrypted image file
+    Encrypt(EncryptCommand),
+    /// Decrypt an encrypted image file to an image
+    Decrypt(DecryptCommand),
+    /// Send an image or encrypted image file to the server
     Load(LoadCommand),
     /// Rescale the image stored on the server
     Rescale(RescaleCommand),
@@ -29,16 +83,64 @@ pub enum Command {
     Invert,
     /// Turn the image stored on the server into grayscale
     Grayscale,
+    /// Reduce the image stored on the server to a few levels per channel
+    Posterise(PosteriseCommand),
+    /// Blur the image stored on the server
+    Blur(BlurCommand),
+    /// Darken the image stored on the server towards its corners
+    Vignette(VignetteCommand),
+    /// Apply a linear gradient to the image stored on the server
+    Gradient(GradientCommand),
+    /// Apply the brightness of an image as a mask to the image stored on the server
+    Mask(MaskCommand),
+    /// Limit later operations on the image stored on the server to where a mask image is set
+    Select(SelectCommand),
+    /// Run a chain of operations on the image stored on the server, keeping only the final result
+    Pipeline(PipelineCommand),
+    /// Download the image stored on the server
+    Fetch,
+    /// List the slots holding an image on the server
+    Slots,
+    /// Delete the image stored on the server
+    Delete,
+    /// Compute the absolute difference between the stored image and the image in another slot
+    Difference(DifferenceCommand),
+    /// Measure the difference between the stored image and the image in another slot
+    Compare(CompareCommand),
+    /// Find where a template image appears in the image stored on the server
+    Match(MatchCommand),
+    /// Show the progress of a job, or store its result if it has finished
+    Status(JobCommand),
+    /// Wait for a job to finish and store its result
+    Wait(JobCommand),
+    /// Stop a job and discard its result
+    Cancel(JobCommand),
 }
 
 #[derive(Debug, Args)]
-pub struct LoadCommand {
+pub struct EncryptCommand {
     /// The path to the image
+    pub input: PathBuf,
+    /// The path to write the encrypted image file to
+    pub output: PathBuf,
+}
+
+#[derive(Debug, Args)]
+pub struct DecryptCommand {
+    /// The path to the encrypted image file
+    pub input: PathBuf,
+    /// The path to write the image to
+    pub output: PathBuf,
+}
+
+#[derive(Debug, Args)]
+pub struct LoadCommand {
+    /// The path to the image or encrypted image file
     pub file: PathBuf,
 }
 
 #[derive(Debug, Args)]
-#[clap(group(ArgGroup::new("interpolation").required(true).args(&["bilinear", "nearest"])))]
+#[clap(group(ArgGroup::new("interpolation").required(true).args(&["bilinear", "nearest", "area"])))]
 pub struct RescaleCommand {
     /// Use bilinear interpolation
     #[arg(long)]
@@ -46,8 +148,137 @@ pub struct RescaleCommand {
     // Use nearest-neighbour interpolation
     #[arg(long)]
     pub nearest: bool,
+    /// Average the pixels every new pixel covers, for downscaling
+    #[arg(long)]
+    pub area: bool,
     /// The new width of the image
     pub width: u16,
     /// The new height of the image
     pub height: u16,
 }
+
+#[derive(Debug, Args)]
+pub struct PosteriseCommand {
+    /// The number of levels per channel, like 2 for 1-bit or 16 for 4-bit output
+    #[arg(value_parser = clap::value_parser!(u16).range(2..))]
+    pub levels: u16,
+    /// How to distribute the rounding error between pixels
+    #[arg(long, value_enum, default_value_t)]
+    pub dither: Dither,
+}
+
+#[derive(Debug, Args)]
+pub struct DifferenceCommand {
+    /// The name of the slot holding the image to compare with
+    pub other: String,
+}
+
+#[derive(Debug, Args)]
+pub struct CompareCommand {
+    /// The name of the slot holding the image to compare with
+    pub other: String,
+    /// The metric to measure the difference with
+    #[arg(long, value_enum)]
+    pub metric: Metric,
+}
+
+#[derive(Debug, Args)]
+pub struct MatchCommand {
+    /// The path to the template image or encrypted image file
+    pub template: PathBuf,
+    /// Let the server find the best match, instead of only finding it after decrypting the scores
+    #[arg(long)]
+    pub locate: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct JobCommand {
+    /// The ID of the job, as printed when starting it with --detach
+    pub id: u64,
+}
+
+#[derive(Debug, Args)]
+pub struct VignetteCommand {
+    /// How much to darken the corners, from 0 to 1
+    #[arg(long, default_value_t = 0.5)]
+    pub strength: f32,
+}
+
+#[derive(Debug, Args)]
+pub struct BlurCommand {
+    /// How many pixels in every direction to average each pixel with
+    #[arg(long, default_value_t = 1)]
+    pub radius: u16,
+}
+
+#[derive(Debug, Args)]
+pub struct GradientCommand {
+    /// The direction of the gradient in degrees, counterclockwise from left to right
+    #[arg(long, default_value_t = 0.0, allow_hyphen_values = true)]
+    pub angle: f32,
+    /// The weight at the start of the gradient
+    #[arg(long, default_value_t = 1.0, allow_hyphen_values = true)]
+    pub from: f32,
+    /// The weight at the end of the gradient
+    #[arg(long, default_value_t = 0.0, allow_hyphen_values = true)]
+    pub to: f32,
+    /// Whether to multiply with the weights or add them as a fraction of the largest value
+    #[arg(long, value_enum, default_value_t)]
+    pub blend: Blend,
+}
+
+#[derive(Debug, Args)]
+pub struct PipelineCommand {
+    /// The operations in order, each given by its name followed by its comma-separated arguments
+    /// after an equals sign: crop=X,Y,WxH, rescale=WxH[,nearest|bilinear|area], invert, grayscale,
+    /// posterise=LEVELS[,DITHER], blur[=RADIUS], vignette[=STRENGTH],
+    /// gradient=ANGLE,FROM,TO[,BLEND] and difference=SLOT
+    #[arg(required = true, allow_hyphen_values = true)]
+    pub operations: Vec<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct SelectCommand {
+    /// The path to the mask image, taken from its alpha channel if it has one and from its
+    /// brightness otherwise; leave out to clear the selection
+    pub file: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+pub struct MaskCommand {
+    /// The path to the mask image, which is stretched to the size of the stored image
+    pub file: PathBuf,
+    /// Whether to multiply with the mask or add it as a fraction of the largest value
+    #[arg(long, value_enum, default_value_t)]
+    pub blend: Blend,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(arguments: &[&str]) -> Result<Arguments, clap::Error> {
+        Arguments::try_parse_from(arguments)?.check()
+    }
+
+    #[test]
+    fn storing_flags_are_rejected_for_compare_and_match() {
+        for command in [
+            &["compare", "other", "--metric", "mse"][..],
+            &["match", "template.png"],
+        ] {
+            for flags in [&["--in-place"][..], &["--store-as", "result"]] {
+                let arguments = [&["fheip"][..], command, flags].concat();
+                let error = check(&arguments).unwrap_err();
+                assert_eq!(error.kind(), ErrorKind::ArgumentConflict);
+            }
+            assert!(check(&[&["fheip"][..], command, &["--slot", "other"]].concat()).is_ok());
+        }
+    }
+
+    #[test]
+    fn storing_flags_are_accepted_for_image_results() {
+        assert!(check(&["fheip", "invert", "--in-place"]).is_ok());
+        assert!(check(&["fheip", "difference", "other", "--store-as", "result"]).is_ok());
+    }
+}
@@ -1,17 +1,36 @@
 use std::error::Error;
-use std::io::BufWriter;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
 use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
-use log::info;
+use log::{debug, info, log, Level};
 
-use crate::crypt::{decrypt_image, encrypt_image, ClientKeyType};
-use crate::image::{EncryptedImage, PlaintextImage};
-use crate::message::Message;
+use crate::crypt::digest::Digest;
+use crate::crypt::key::{load_or_compute_key_digest, SessionKey, SessionToken};
+use crate::crypt::{
+    decrypt_image, encrypt_image, encrypt_samples, ClientKeyType, EncryptedImageData,
+    ServerKeyType, Width,
+};
+use crate::image::animation::{EncryptedAnimation, PlaintextAnimation};
+use crate::image::metadata::MetadataPolicy;
+use crate::image::{EncryptedImage, Image, PlaintextImage};
+use crate::message::{Message, Request};
+use crate::protocol::{self, FrameType, Hello};
 
 #[derive(Debug)]
 pub struct Client {
     address: String,
     key: ClientKeyType,
+    /// The server key belonging to the client key, and the path it is stored at.
+    server_key: SessionKey,
+    server_key_path: PathBuf,
+    /// The digest of the server key, only computed once it is needed.
+    key_digest: OnceLock<Digest>,
+    metadata_policy: MetadataPolicy,
+    /// The token of the session messages are sent in once it is opened.
+    session: Option<SessionToken>,
 }
 
 impl Client {
@@ -22,16 +41,53 @@ impl Client {
     /// ```
     /// let connection = Connection::new("127.0.0.1:34347");
     /// ```
-    pub fn new(address: &str, key: ClientKeyType) -> Self {
+    pub fn new(
+        address: &str,
+        key: ClientKeyType,
+        server_key: ServerKeyType,
+        server_key_path: &Path,
+        metadata_policy: MetadataPolicy,
+    ) -> Self {
         Self {
             address: address.to_string(),
             key,
+            server_key: SessionKey(server_key),
+            server_key_path: server_key_path.to_path_buf(),
+            key_digest: OnceLock::new(),
+            metadata_policy,
+            session: None,
+        }
+    }
+
+    /// Open a session for the server key, so later messages apply to this client's images.
+    ///
+    /// The session whose token is stored at the given path is resumed if the server still holds
+    /// it with the same key. Otherwise a new session is registered and its token stored there.
+    pub fn open_session(&mut self, token_path: &Path) -> Result<(), Box<dyn Error>> {
+        if let Ok(file) = File::open(token_path) {
+            let token = bincode::deserialize_from(BufReader::new(file))?;
+            let resume = Message::Resume(token, self.key_digest()?);
+            if let Some(Message::Session(token)) = self.send_message(resume)? {
+                self.session = Some(token);
+                return Ok(());
+            }
+        }
+
+        info!("Registering a new session");
+        match self.send_message(Message::Register(self.server_key.clone()))? {
+            Some(Message::Session(token)) => {
+                bincode::serialize_into(BufWriter::new(File::create(token_path)?), &token)?;
+                self.session = Some(token);
+                Ok(())
+            }
+            _ => Err("Server did not register the session".into()),
         }
     }
 
     /// Send a message to the server and wait for a response.
     ///
-    /// Returns the message extracted from the response if there is one.
+    /// Returns the message extracted from the response, or `None` if the server only acknowledged
+    /// the request. Errors reported by the server are returned as errors.
     ///
     /// # Examples
     ///
@@ -40,26 +96,138 @@ impl Client {
     /// let answer = connection.send_message(Message::Ping).unwrap();
     /// ```
     pub fn send_message(&self, message: Message) -> Result<Option<Message>, Box<dyn Error>> {
+        self.send_request(Request::new(message))
+    }
+
+    /// Send a request to the server, like [`Client::send_message`] but for a message run on a
+    /// slot, stored or run as a job.
+    pub fn send_request(&self, request: Request) -> Result<Option<Message>, Box<dyn Error>> {
+        self.exchange(request, |writer, request| {
+            let frame_type = FrameType::of_request(&request);
+            protocol::write_message(writer, frame_type, request)
+        })
+    }
+
+    /// Encrypt a still image and send it to the server in the request built around it, encrypting
+    /// its rows only as they are sent so the whole encrypted image is never held in memory.
+    pub fn send_image(
+        &self,
+        image: &PlaintextImage,
+        request: impl FnOnce(Message) -> Request,
+    ) -> Result<Option<Message>, Box<dyn Error>> {
+        let header = self.encrypt_image(
+            &Image::new(
+                Vec::new(),
+                image.size.width,
+                image.size.height,
+                image.color_type,
+                image.bit_depth,
+            )
+            .with_metadata(image.metadata.clone()),
+        );
+
+        self.exchange(request(Message::Image(header)), |mut writer, request| {
+            protocol::write_frame(&mut writer, FrameType::of_request(&request), &request)?;
+            let row_length = image.size.width as usize * image.channel_count() as usize;
+            let width = Width::for_bits(image.bit_depth.bits());
+            for rows in image
+                .data
+                .chunks(protocol::row_frame_length(width, row_length))
+            {
+                let rows = encrypt_samples(rows, image.bit_depth, &self.key);
+                protocol::write_rows(&mut writer, &rows, row_length)?;
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Send a request in the session, writing it with the given function, and read the answer.
+    fn exchange(
+        &self,
+        mut request: Request,
+        write: impl FnOnce(BufWriter<&TcpStream>, Request) -> Result<(), Box<dyn Error>>,
+    ) -> Result<Option<Message>, Box<dyn Error>> {
+        if request.message.needs_session() {
+            request.session = self.session;
+        }
         let stream = TcpStream::connect(&self.address)?;
-        let writer = BufWriter::new(&stream);
 
-        info!("Sending {:?}", message);
-        bincode::serialize_into(writer, &message)?;
-        if message.expect_answer() {
-            let answer = bincode::deserialize_from(&stream)?;
-            info!("Received answer {:?}", answer);
+        // polling a job would flood the log
+        let level = match request.message {
+            Message::JobStatus(_) => Level::Debug,
+            _ => Level::Info,
+        };
+
+        log!(level, "Sending {:?}", request);
+        write(BufWriter::new(&stream), request)?;
+
+        let mut reader = BufReader::new(&stream);
+        let header = protocol::read_header(&mut reader)?;
+        protocol::check_version(header.version)?;
+        if header.frame_type != FrameType::Answer {
+            return Err(format!("Expected an answer but received {:?}", header.frame_type).into());
+        }
+        let mut answer = protocol::read_payload(&mut reader, &header)?;
+        protocol::read_rows(&mut reader, &mut answer)?;
+        log!(level, "Received answer {:?}", answer);
 
-            return Ok(Some(answer));
+        match answer {
+            Message::Done => Ok(None),
+            Message::Error { code, description } => {
+                Err(format!("Server error ({:?}): {}", code, description).into())
+            }
+            answer => Ok(Some(answer)),
         }
+    }
+
+    /// Exchange what client and server support, failing if they cannot work together.
+    ///
+    /// Returns what the server supports.
+    pub fn hello(&self) -> Result<Hello, Box<dyn Error>> {
+        let stream = TcpStream::connect(&self.address)?;
+        protocol::write_frame(BufWriter::new(&stream), FrameType::Hello, &Hello::current())?;
 
-        Ok(None)
+        let mut reader = BufReader::new(&stream);
+        let header = protocol::read_header(&mut reader)?;
+        if header.frame_type != FrameType::Hello {
+            return Err(format!("Expected a hello but received {:?}", header.frame_type).into());
+        }
+        let hello: Hello = protocol::read_payload(&mut reader, &header)?;
+        debug!("Received {:?}", hello);
+        hello.check_compatible()?;
+
+        Ok(hello)
     }
 
     pub fn encrypt_image(&self, image: &PlaintextImage) -> EncryptedImage {
-        encrypt_image(image, &self.key)
+        encrypt_image(image, &self.key, self.metadata_policy)
     }
 
     pub fn decrypt_image(&self, image: &EncryptedImage) -> PlaintextImage {
         decrypt_image(image, &self.key)
     }
+
+    pub fn encrypt_animation(&self, animation: &PlaintextAnimation) -> EncryptedAnimation {
+        animation.map_frames(|frame| self.encrypt_image(frame))
+    }
+
+    pub fn decrypt_animation(&self, animation: &EncryptedAnimation) -> PlaintextAnimation {
+        animation.map_frames(|frame| self.decrypt_image(frame))
+    }
+
+    pub fn decrypt_value(&self, value: &EncryptedImageData) -> u64 {
+        self.key.decrypt(value)
+    }
+
+    /// The digest of the server key, stored in encrypted image files to recognise the key pair
+    /// their ciphertexts belong to.
+    pub fn key_digest(&self) -> Result<Digest, Box<dyn Error>> {
+        if let Some(key_digest) = self.key_digest.get() {
+            return Ok(*key_digest);
+        }
+
+        let key_digest = load_or_compute_key_digest(&self.server_key_path, &self.server_key.0)?;
+        Ok(*self.key_digest.get_or_init(|| key_digest))
+    }
 }
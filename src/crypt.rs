@@ -1,23 +1,191 @@
-use tfhe::integer::{RadixCiphertextBig, RadixClientKey, ServerKey};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tfhe::integer::{IntegerCiphertext, RadixCiphertextBig, RadixClientKey, ServerKey};
+use tfhe::shortint::prelude::PARAM_MESSAGE_2_CARRY_2;
+use tfhe::shortint::Parameters;
 
-use crate::image::{EncryptedImage, PlaintextImage};
+use crate::image::metadata::{AttachedMetadata, MetadataPolicy};
+use crate::image::{BitDepth, EncryptedImage, PlaintextImage};
 
+pub mod digest;
 pub mod key;
 pub mod operations;
 
-pub const NUM_BLOCKS: usize = 8;
+/// The parameter set all keys are generated with.
+pub const PARAMETER_SET: ParameterSet = ParameterSet::Message2Carry2;
+
+/// The number of message bits in each block of a ciphertext with [`PARAMETER_SET`].
+pub const BLOCK_BITS: u32 = 2;
 
 pub type EncryptedImageData = RadixCiphertextBig;
 pub type ServerKeyType = ServerKey;
 pub type ClientKeyType = RadixClientKey;
 
-pub fn encrypt_image(image: &PlaintextImage, key: &ClientKeyType) -> EncryptedImage {
+/// Identifies the TFHE parameters a key was generated with, since ciphertexts can only be used
+/// with keys of the same parameters.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy)]
+pub enum ParameterSet {
+    Message2Carry2,
+}
+
+impl ParameterSet {
+    pub fn parameters(&self) -> Parameters {
+        match self {
+            ParameterSet::Message2Carry2 => PARAM_MESSAGE_2_CARRY_2,
+        }
+    }
+}
+
+/// The number of bits a ciphertext can hold, which is always a whole number of blocks.
+///
+/// Every block makes operations on a ciphertext more expensive, so images are encrypted at the
+/// width of their samples and operations widen them only as far as their intermediate results need.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Clone, Copy)]
+pub struct Width {
+    bits: u32,
+}
+
+impl Width {
+    pub const EIGHT: Width = Width { bits: 8 };
+    pub const SIXTEEN: Width = Width { bits: 16 };
+
+    /// The narrowest width that can hold values of the given number of bits.
+    pub fn for_bits(bits: u32) -> Self {
+        Self {
+            bits: bits.div_ceil(BLOCK_BITS) * BLOCK_BITS,
+        }
+    }
+
+    /// The width of an existing ciphertext.
+    pub fn of(x: &EncryptedImageData) -> Self {
+        Self {
+            bits: x.blocks().len() as u32 * BLOCK_BITS,
+        }
+    }
+
+    pub fn bits(&self) -> u32 {
+        self.bits
+    }
+
+    /// The largest value a ciphertext of this width can hold, limited to what fits 64 bits.
+    pub fn max_value(&self) -> u64 {
+        // a width without bits holds only zero and a wider one more than any shift can express
+        u64::MAX
+            .checked_shr(u64::BITS.saturating_sub(self.bits))
+            .unwrap_or(0)
+    }
+
+    pub fn num_blocks(&self) -> usize {
+        (self.bits / BLOCK_BITS) as usize
+    }
+
+    /// This width extended by the given number of bits.
+    pub fn with_headroom(&self, bits: u32) -> Self {
+        Self::for_bits(self.bits + bits)
+    }
+}
+
+/// The number of fractional bits weights are represented with.
+///
+/// More bits reduce rounding errors, but every two bits add a block to the intermediate values of
+/// weighted operations, which makes them slower. Received precisions go through the same clamping
+/// as new ones.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy)]
+#[serde(from = "u32", into = "u32")]
+pub struct Precision {
+    fractional_bits: u32,
+}
+
+impl Precision {
+    /// The most fractional bits, which keeps two chained weights of 16-bit samples within 64 bits.
+    pub const MAX_BITS: u32 = 16;
+
+    pub fn new(fractional_bits: u32) -> Self {
+        Self {
+            fractional_bits: fractional_bits.clamp(1, Self::MAX_BITS),
+        }
+    }
+
+    pub fn fractional_bits(&self) -> u32 {
+        self.fractional_bits
+    }
+
+    /// The fixed-point representation of one.
+    pub fn one(&self) -> u64 {
+        1 << self.fractional_bits
+    }
+
+    /// Convert a weight to fixed-point, treating negative weights as zero.
+    pub fn weight(&self, weight: f32) -> u64 {
+        (f64::from(weight.max(0.0)) * self.one() as f64).round() as u64
+    }
+}
+
+impl Default for Precision {
+    fn default() -> Self {
+        Self::new(8)
+    }
+}
+
+impl From<u32> for Precision {
+    fn from(fractional_bits: u32) -> Self {
+        Self::new(fractional_bits)
+    }
+}
+
+impl From<Precision> for u32 {
+    fn from(precision: Precision) -> Self {
+        precision.fractional_bits
+    }
+}
+
+/// Encrypt samples of the given bit depth at the narrowest width that holds them.
+pub fn encrypt_samples(
+    samples: &[u16],
+    bit_depth: BitDepth,
+    key: &ClientKeyType,
+) -> Vec<EncryptedImageData> {
+    let width = Width::for_bits(bit_depth.bits());
+
+    samples
+        .iter()
+        .map(|x| key.as_ref().encrypt_radix(*x as u64, width.num_blocks()))
+        .collect()
+}
+
+pub fn encrypt_image(
+    image: &PlaintextImage,
+    key: &ClientKeyType,
+    metadata_policy: MetadataPolicy,
+) -> EncryptedImage {
     EncryptedImage::new(
-        image.data.iter().map(|x| key.encrypt(*x as u64)).collect(),
+        encrypt_samples(&image.data, image.bit_depth, key),
         image.size.width,
         image.size.height,
         image.color_type,
+        image.bit_depth,
     )
+    .with_metadata(match (&image.metadata, metadata_policy) {
+        (AttachedMetadata::Plaintext(metadata), MetadataPolicy::Plaintext) => {
+            AttachedMetadata::Plaintext(metadata.clone())
+        }
+        (AttachedMetadata::Plaintext(metadata), MetadataPolicy::Display) => {
+            AttachedMetadata::Plaintext(metadata.display_only())
+        }
+        (AttachedMetadata::Plaintext(metadata), MetadataPolicy::Encrypted) => {
+            AttachedMetadata::Encrypted(
+                bincode::serialize(metadata)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|x| {
+                        key.as_ref()
+                            .encrypt_radix(x as u64, Width::EIGHT.num_blocks())
+                    })
+                    .collect(),
+            )
+        }
+        _ => AttachedMetadata::None,
+    })
 }
 
 pub fn decrypt_image(image: &EncryptedImage, key: &ClientKeyType) -> PlaintextImage {
@@ -25,10 +193,57 @@ pub fn decrypt_image(image: &EncryptedImage, key: &ClientKeyType) -> PlaintextIm
         image
             .data
             .iter()
-            .map(|x| key.decrypt::<u64, _>(x) as u8)
-            .collect::<Vec<u8>>(),
+            .map(|x| {
+                key.decrypt::<u64, _>(x)
+                    .min(image.bit_depth.max_value() as u64) as u16
+            })
+            .collect::<Vec<u16>>(),
         image.size.width,
         image.size.height,
         image.color_type,
+        image.bit_depth,
     )
+    .with_metadata(match &image.metadata {
+        AttachedMetadata::None => AttachedMetadata::None,
+        AttachedMetadata::Plaintext(metadata) => AttachedMetadata::Plaintext(metadata.clone()),
+        AttachedMetadata::Encrypted(data) => {
+            let data = data
+                .iter()
+                .map(|x| key.decrypt::<u64, _>(x) as u8)
+                .collect::<Vec<u8>>();
+
+            match bincode::deserialize(&data) {
+                Ok(metadata) => AttachedMetadata::Plaintext(metadata),
+                Err(error) => {
+                    warn!("Discarding unreadable metadata: {}", error);
+                    AttachedMetadata::None
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn max_values_cover_all_widths() {
+        assert_eq!(Width { bits: 0 }.max_value(), 0);
+        assert_eq!(Width { bits: BLOCK_BITS }.max_value(), 3);
+        assert_eq!(Width::EIGHT.max_value(), 255);
+        assert_eq!(Width::SIXTEEN.max_value(), 65535);
+        assert_eq!(Width::for_bits(64).max_value(), u64::MAX);
+        assert_eq!(Width::for_bits(80).max_value(), u64::MAX);
+    }
+
+    #[test]
+    fn clamps_received_precisions() {
+        let received = |bits: u32| bincode::deserialize::<Precision>(&bits.to_le_bytes()).unwrap();
+
+        assert_eq!(received(0), Precision::new(1));
+        assert_eq!(received(0).fractional_bits(), 1);
+        assert_eq!(received(99).fractional_bits(), Precision::MAX_BITS);
+        assert_eq!(received(8), Precision::new(8));
+    }
 }
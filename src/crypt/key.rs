@@ -1,17 +1,89 @@
 use std::error::Error;
-use std::fs::File;
+use std::fmt::{Debug, Formatter};
+use std::fs::{self, File};
 use std::io::{BufReader, BufWriter};
 use std::path::Path;
 
 use log::info;
+use serde::{Deserialize, Serialize};
+use tfhe::core_crypto::seeders::new_seeder;
 use tfhe::integer::gen_keys_radix;
-use tfhe::shortint::prelude::PARAM_MESSAGE_2_CARRY_2;
 
-use crate::crypt::{ClientKeyType, ServerKeyType, NUM_BLOCKS};
+use crate::crypt::digest::{Digest, Sha256};
+use crate::crypt::{ClientKeyType, ServerKeyType, Width, PARAMETER_SET};
 
 pub fn generate_keys() -> (ClientKeyType, ServerKeyType) {
     info!("Generating keys");
-    gen_keys_radix(&PARAM_MESSAGE_2_CARRY_2, NUM_BLOCKS)
+    // ciphertexts are encrypted with an explicit width, the key's own block count is only a default
+    gen_keys_radix(&PARAMETER_SET.parameters(), Width::SIXTEEN.num_blocks())
+}
+
+/// A server key sent to register a session, which lets the server compute on ciphertexts without
+/// being able to decrypt them.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SessionKey(pub ServerKeyType);
+
+impl Debug for SessionKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Server key")
+    }
+}
+
+/// A random secret handed out when a session is registered, which is needed to use the session.
+#[derive(PartialEq, Eq, Hash, Serialize, Deserialize, Clone, Copy)]
+pub struct SessionToken(u128);
+
+impl SessionToken {
+    /// Draw a new token from the random source of the operating system or CPU.
+    pub fn generate() -> Self {
+        Self(new_seeder().seed().0)
+    }
+}
+
+impl Debug for SessionToken {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        // the token is as good as a password, so it never ends up in logs
+        write!(f, "Session token")
+    }
+}
+
+/// Compute the SHA-256 digest of a server key, which is public, to recognise the key a session or
+/// ciphertexts belong to.
+pub fn key_digest(key: &ServerKeyType) -> Result<Digest, Box<dyn Error>> {
+    let mut hash = Sha256::new();
+    bincode::serialize_into(&mut hash, key)?;
+
+    Ok(hash.finish())
+}
+
+/// Load the digest of the server key stored at the given path, computing it and storing it next to
+/// the key if it is missing or older than the key.
+pub fn load_or_compute_key_digest(
+    server_key_path: &Path,
+    key: &ServerKeyType,
+) -> Result<Digest, Box<dyn Error>> {
+    let key_path = server_key_path.with_extension("key");
+    let digest_path = server_key_path.with_extension("digest");
+    let modified = |path: &Path| {
+        path.metadata()
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    };
+
+    if modified(&digest_path) >= modified(&key_path) {
+        if let Some(digest) = fs::read(&digest_path)
+            .ok()
+            .and_then(|digest| digest.try_into().ok())
+        {
+            return Ok(digest);
+        }
+    }
+
+    info!("Computing the digest of the server key");
+    let digest = key_digest(key)?;
+    fs::write(digest_path, digest)?;
+
+    Ok(digest)
 }
 
 pub fn generate_keys_to_file(
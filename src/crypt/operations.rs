@@ -1,22 +1,363 @@
 use std::sync::Arc;
 use std::thread;
 
-use crate::crypt::{EncryptedImageData, ServerKeyType};
+use tfhe::integer::IntegerCiphertext;
+
+use crate::crypt::{EncryptedImageData, Precision, ServerKeyType, Width};
 
 const ONE_THIRD: f32 = 1.0 / 3.0;
 
-pub fn average_three(x: [&EncryptedImageData; 3], key: &ServerKeyType) -> EncryptedImageData {
-    weight_multiplication(&add_three(x, key), ONE_THIRD, key)
+/// Extend a ciphertext to a wider width without changing its value.
+///
+/// Ciphertexts that are already at least as wide are returned unchanged.
+pub fn widen(x: &EncryptedImageData, width: Width, key: &ServerKeyType) -> EncryptedImageData {
+    if Width::of(x) >= width {
+        return x.clone();
+    }
+
+    let mut blocks = propagated(x, key).blocks().to_vec();
+    let padding: EncryptedImageData =
+        key.create_trivial_zero_radix(width.num_blocks() - blocks.len());
+    blocks.extend_from_slice(padding.blocks());
+
+    EncryptedImageData::from_blocks(blocks)
+}
+
+/// Reduce a ciphertext to a narrower width, keeping its value modulo the new width.
+///
+/// Ciphertexts that are already at most as wide are returned unchanged. Use [`clamp`] if the value
+/// may not fit.
+pub fn narrow(x: &EncryptedImageData, width: Width, key: &ServerKeyType) -> EncryptedImageData {
+    if Width::of(x) <= width {
+        return x.clone();
+    }
+
+    let mut blocks = propagated(x, key).blocks().to_vec();
+    blocks.truncate(width.num_blocks());
+
+    EncryptedImageData::from_blocks(blocks)
+}
+
+/// Move pending carries into their blocks, so blocks can be added or removed at the top.
+fn propagated(x: &EncryptedImageData, key: &ServerKeyType) -> EncryptedImageData {
+    let mut x = x.clone();
+    if !x.blocks().iter().all(|block| block.carry_is_empty()) {
+        key.full_propagate_parallelized(&mut x);
+    }
+
+    x
+}
+
+/// Limit a value to the largest value of a width and reduce it to that width.
+pub fn clamp(x: &EncryptedImageData, width: Width, key: &ServerKeyType) -> EncryptedImageData {
+    if Width::of(x) <= width {
+        return x.clone();
+    }
+
+    let max_value: EncryptedImageData =
+        key.create_trivial_radix(width.max_value(), Width::of(x).num_blocks());
+
+    narrow(&key.min_parallelized(x, &max_value), width, key)
+}
+
+/// Add two values, saturating at the largest value of the wider one's width.
+pub fn saturating_add(
+    x: &EncryptedImageData,
+    y: &EncryptedImageData,
+    key: &ServerKeyType,
+) -> EncryptedImageData {
+    let width = Width::of(x).max(Width::of(y));
+    let sum_width = width.with_headroom(1);
+
+    clamp(
+        &key.add_parallelized(&widen(x, sum_width, key), &widen(y, sum_width, key)),
+        width,
+        key,
+    )
+}
+
+/// Subtract two values, saturating at zero.
+pub fn saturating_sub(
+    x: &EncryptedImageData,
+    y: &EncryptedImageData,
+    key: &ServerKeyType,
+) -> EncryptedImageData {
+    let width = Width::of(x).max(Width::of(y));
+    let (x, y) = (widen(x, width, key), widen(y, width, key));
+
+    key.sub_parallelized(&x, &key.min_parallelized(&x, &y))
+}
+
+/// A value multiplied by fixed-point weights without dividing out their scale yet, so chains of
+/// weighted operations only round once at the end.
+#[derive(Clone)]
+pub struct Scaled {
+    value: EncryptedImageData,
+    /// The number of fractional bits of the value.
+    fractional_bits: u32,
+    /// An upper bound of the value, to know how wide it needs to be.
+    max_value: u128,
+    /// The width to round the value back to.
+    width: Width,
+}
+
+impl Scaled {
+    pub fn new(x: &EncryptedImageData) -> Self {
+        let width = Width::of(x);
+
+        Self {
+            value: x.clone(),
+            fractional_bits: 0,
+            max_value: width.max_value() as u128,
+            width,
+        }
+    }
+
+    /// Multiply by a fixed-point weight of the given precision.
+    pub fn weighted(&self, weight: u64, precision: Precision, key: &ServerKeyType) -> Self {
+        let max_value = self.max_value * weight as u128;
+
+        Self {
+            value: key.scalar_mul_parallelized(
+                &widen(&self.value, Width::for_bits(bit_length(max_value)), key),
+                weight,
+            ),
+            fractional_bits: self.fractional_bits + precision.fractional_bits(),
+            max_value,
+            width: self.width,
+        }
+    }
+
+    /// Add a value with the same scale.
+    pub fn add(&self, other: &Scaled, key: &ServerKeyType) -> Self {
+        assert_eq!(self.fractional_bits, other.fractional_bits);
+        let max_value = self.max_value + other.max_value;
+        let width = Width::for_bits(bit_length(max_value));
+
+        Self {
+            value: key.add_parallelized(
+                &widen(&self.value, width, key),
+                &widen(&other.value, width, key),
+            ),
+            fractional_bits: self.fractional_bits,
+            max_value,
+            width: self.width.max(other.width),
+        }
+    }
+
+    /// Divide out the scale, rounding to the nearest integer and clamping to the original width.
+    pub fn round(&self, key: &ServerKeyType) -> EncryptedImageData {
+        if self.fractional_bits == 0 {
+            return clamp(&self.value, self.width, key);
+        }
+
+        let half = 1_u64 << (self.fractional_bits - 1);
+        let value = key.scalar_add_parallelized(
+            &widen(
+                &self.value,
+                Width::for_bits(bit_length(self.max_value + half as u128)),
+                key,
+            ),
+            half,
+        );
+
+        clamp(
+            &key.unchecked_scalar_right_shift_parallelized(&value, self.fractional_bits as usize),
+            self.width,
+            key,
+        )
+    }
+}
+
+/// Division by a constant, rounding to the nearest integer, done exactly by multiplying with a
+/// reciprocal that has enough bits for every value up to a bound.
+pub struct Divisor {
+    divisor: u64,
+    multiplier: u64,
+    shift: u32,
+    /// An upper bound of the values to divide.
+    max_value: u128,
+}
+
+impl Divisor {
+    /// Returns `None` if the divisor is zero or the reciprocal does not fit 64 bits.
+    pub fn new(divisor: u64, max_value: u128) -> Option<Self> {
+        if divisor == 0 {
+            return None;
+        }
+
+        // with as many bits below the point as the dividend and the divisor have together, the
+        // error of the rounded up reciprocal stays below the gap to the next multiple of the divisor
+        let dividend_bits = bit_length(max_value.checked_add(divisor as u128 / 2)?);
+        let shift = dividend_bits + bit_length(divisor as u128 - 1);
+        let multiplier = 1_u128.checked_shl(shift)?.div_ceil(divisor as u128);
+
+        Some(Self {
+            divisor,
+            multiplier: multiplier.try_into().ok()?,
+            shift,
+            max_value,
+        })
+    }
+
+    /// Divide a value that is at most the bound, widening it as needed for the product.
+    pub fn divide(&self, x: &EncryptedImageData, key: &ServerKeyType) -> EncryptedImageData {
+        let half = self.divisor / 2;
+        let product_bits = bit_length((self.max_value + half as u128) * self.multiplier as u128);
+        let value =
+            key.scalar_add_parallelized(&widen(x, Width::for_bits(product_bits), key), half);
+
+        key.unchecked_scalar_right_shift_parallelized(
+            &key.scalar_mul_parallelized(&value, self.multiplier),
+            self.shift as usize,
+        )
+    }
+}
+
+/// The number of bits needed to represent a value.
+fn bit_length(value: u128) -> u32 {
+    u128::BITS - value.leading_zeros()
+}
+
+/// The absolute difference between two values.
+pub fn absolute_difference(
+    x: &EncryptedImageData,
+    y: &EncryptedImageData,
+    key: &ServerKeyType,
+) -> EncryptedImageData {
+    let width = Width::of(x).max(Width::of(y));
+    let (x, y) = (widen(x, width, key), widen(y, width, key));
+
+    key.sub_parallelized(&key.max_parallelized(&x, &y), &key.min_parallelized(&x, &y))
+}
+
+/// Choose between two values depending on an encrypted condition that is either zero or one.
+pub fn select(
+    condition: &EncryptedImageData,
+    if_true: &EncryptedImageData,
+    if_false: &EncryptedImageData,
+    key: &ServerKeyType,
+) -> EncryptedImageData {
+    let width = Width::of(if_true).max(Width::of(if_false));
+    let (if_true, if_false) = (widen(if_true, width, key), widen(if_false, width, key));
+    // negating a condition of one gives a mask with all bits set
+    let mask = key.neg_parallelized(&narrow(&widen(condition, width, key), width, key));
+
+    key.bitxor_parallelized(
+        &if_false,
+        &key.bitand_parallelized(&mask, &key.bitxor_parallelized(&if_true, &if_false)),
+    )
+}
+
+/// Mix two values by an encrypted mask, giving `(mask * x + (max_value - mask) * y) / max_value`.
+pub fn masked_blend(
+    x: &EncryptedImageData,
+    y: &EncryptedImageData,
+    mask: &EncryptedImageData,
+    max_value: u16,
+    key: &ServerKeyType,
+) -> EncryptedImageData {
+    let width = Width::of(x).max(Width::of(y));
+    // both products need the bits of the mask and their sum one more
+    let product_width = width.with_headroom(Width::of(mask).bits() + 1);
+    let (x, y, mask) = (
+        widen(x, product_width, key),
+        widen(y, product_width, key),
+        widen(mask, product_width, key),
+    );
+    let full_mask: EncryptedImageData =
+        key.create_trivial_radix(max_value as u64, product_width.num_blocks());
+    let inverse_mask = saturating_sub(&full_mask, &mask, key);
+
+    let sum = key.add_parallelized(
+        &key.mul_parallelized(&x, &mask),
+        &key.mul_parallelized(&y, &inverse_mask),
+    );
+
+    // both products together are at most the largest value times the full mask
+    let divisor = Divisor::new(
+        max_value as u64,
+        width.max_value() as u128 * max_value as u128,
+    )
+    .expect("a 16-bit mask has a 64-bit reciprocal");
+
+    clamp(&divisor.divide(&sum, key), width, key)
+}
+
+/// Square a value, widening it so the result fits.
+pub fn square(x: &EncryptedImageData, key: &ServerKeyType) -> EncryptedImageData {
+    let width = Width::of(x);
+    let x = widen(x, width.with_headroom(width.bits()), key);
+
+    key.mul_parallelized(&x, &x)
+}
+
+/// Add up values in a ciphertext of the given width, which needs to hold the sum.
+pub fn sum(values: &[EncryptedImageData], width: Width, key: &ServerKeyType) -> EncryptedImageData {
+    values.iter().fold(
+        key.create_trivial_zero_radix(width.num_blocks()),
+        |sum, x| key.add_parallelized(&sum, &widen(x, width, key)),
+    )
+}
+
+pub fn average_three(
+    x: [&EncryptedImageData; 3],
+    precision: Precision,
+    key: &ServerKeyType,
+) -> EncryptedImageData {
+    let width = x.iter().map(|x| Width::of(x)).max().unwrap();
+    // the sum of three values needs two more bits
+    let x = x.map(|x| widen(x, width.with_headroom(2), key));
+
+    clamp(
+        &weight_multiplication(
+            &add_three([&x[0], &x[1], &x[2]], key),
+            ONE_THIRD,
+            precision,
+            key,
+        ),
+        width,
+        key,
+    )
 }
 
 pub fn add_three(x: [&EncryptedImageData; 3], key: &ServerKeyType) -> EncryptedImageData {
     key.unchecked_add(&key.unchecked_add(x[0], x[1]), x[2])
 }
 
-pub fn invert_u8(x: &EncryptedImageData, key: &ServerKeyType) -> EncryptedImageData {
-    key.neg_parallelized(&key.scalar_sub_parallelized(x, 255_u64))
+pub fn invert_value(
+    x: &EncryptedImageData,
+    max_value: u16,
+    key: &ServerKeyType,
+) -> EncryptedImageData {
+    let max_value: EncryptedImageData =
+        key.create_trivial_radix(max_value as u64, Width::of(x).num_blocks());
+
+    saturating_sub(&max_value, x, key)
+}
+
+/// Map a value to a level of a step function, given as the threshold from which each step applies
+/// and how much it adds to the level below.
+pub fn step_function(
+    x: &EncryptedImageData,
+    steps: &[(u64, u64)],
+    key: &ServerKeyType,
+) -> EncryptedImageData {
+    let num_blocks = Width::of(x).num_blocks();
+
+    steps.iter().fold(
+        key.create_trivial_zero_radix(num_blocks),
+        |level, &(threshold, increase)| {
+            let threshold: EncryptedImageData = key.create_trivial_radix(threshold, num_blocks);
+            let is_above = key.ge_parallelized(x, &threshold);
+
+            key.add_parallelized(&level, &key.scalar_mul_parallelized(&is_above, increase))
+        },
+    )
 }
 
+/// Interpolate between four values, rounding only the final result.
+#[allow(clippy::too_many_arguments)]
 pub fn bicubic_interpolation(
     a: EncryptedImageData,
     b: EncryptedImageData,
@@ -24,54 +365,131 @@ pub fn bicubic_interpolation(
     d: EncryptedImageData,
     x_weight: f32,
     y_weight: f32,
+    precision: Precision,
     key: Arc<ServerKeyType>,
 ) -> EncryptedImageData {
     let e_key = key.clone();
-    let e = thread::spawn(move || linear_interpolation(a, b, x_weight, e_key));
+    let e = thread::spawn(move || {
+        linear_interpolation(Scaled::new(&a), Scaled::new(&b), x_weight, precision, e_key)
+    });
 
     let f_key = key.clone();
-    let f = thread::spawn(move || linear_interpolation(c, d, x_weight, f_key));
+    let f = thread::spawn(move || {
+        linear_interpolation(Scaled::new(&c), Scaled::new(&d), x_weight, precision, f_key)
+    });
 
     let (e, f) = (e.join().unwrap(), f.join().unwrap());
 
-    linear_interpolation(e, f, y_weight, key)
+    linear_interpolation(e, f, y_weight, precision, key.clone()).round(&key)
 }
 
 pub fn linear_interpolation(
-    x: EncryptedImageData,
-    y: EncryptedImageData,
+    x: Scaled,
+    y: Scaled,
     weight: f32,
+    precision: Precision,
     key: Arc<ServerKeyType>,
-) -> EncryptedImageData {
-    let one_minus_weight = 1.0 - weight;
+) -> Scaled {
+    // the weights add up to exactly one, so the result never exceeds the larger value
+    let weight = precision.weight(weight).min(precision.one());
+    let one_minus_weight = precision.one() - weight;
 
     let x_key = key.clone();
-    let x_scaled = thread::spawn(move || weight_multiplication(&x, one_minus_weight, &x_key));
+    let x_scaled = thread::spawn(move || x.weighted(one_minus_weight, precision, &x_key));
 
     let y_key = key.clone();
-    let y_scaled = thread::spawn(move || weight_multiplication(&y, weight, &y_key));
+    let y_scaled = thread::spawn(move || y.weighted(weight, precision, &y_key));
 
     let (x_scaled, y_scaled) = (x_scaled.join().unwrap(), y_scaled.join().unwrap());
 
-    key.add_parallelized(&x_scaled, &y_scaled)
+    x_scaled.add(&y_scaled, &key)
 }
 
+/// Multiply a value by a non-negative weight, rounding to the nearest integer and saturating at the
+/// largest value of its width.
 pub fn weight_multiplication(
     x: &EncryptedImageData,
     weight: f32,
+    precision: Precision,
     key: &ServerKeyType,
 ) -> EncryptedImageData {
     if weight == 1.0 {
         return x.clone();
     }
-    if weight == 0.0 {
-        return key.unchecked_small_scalar_mul_parallelized(x, 0);
-    }
 
-    let weight = weight_to_integer(weight);
-    key.unchecked_scalar_right_shift_parallelized(&key.scalar_mul_parallelized(x, weight), 8)
+    Scaled::new(x)
+        .weighted(precision.weight(weight), precision, key)
+        .round(key)
 }
 
-fn weight_to_integer(weight: f32) -> u64 {
-    (f64::from(weight) * f64::from(1 << 8)).round() as u64
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What [`Divisor::divide`] computes, on plaintext values.
+    fn divide(divisor: &Divisor, x: u128) -> u128 {
+        ((x + divisor.divisor as u128 / 2) * divisor.multiplier as u128) >> divisor.shift
+    }
+
+    /// Division rounding halves up, which the divisor has to match exactly.
+    fn rounded(x: u128, divisor: u64) -> u128 {
+        (x + divisor as u128 / 2) / divisor as u128
+    }
+
+    #[test]
+    fn divides_every_small_value_exactly() {
+        for divisor in [1, 2, 3, 5, 7, 255, 256, 257] {
+            let max_value = 65535;
+            let exact = Divisor::new(divisor, max_value).unwrap();
+
+            for x in 0..=max_value {
+                assert_eq!(
+                    divide(&exact, x),
+                    rounded(x, divisor),
+                    "{} / {}",
+                    x,
+                    divisor
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn divides_exactly_at_the_boundaries() {
+        for (divisor, max_value) in [
+            (3, 255 * 65535),
+            (65535, 65535 * 65535),
+            (65521, u32::MAX as u128),
+            (u32::MAX as u64, u32::MAX as u128 * 65535),
+        ] {
+            let exact = Divisor::new(divisor, max_value).unwrap();
+            let multiples = [1, 2, max_value / divisor as u128];
+            let values = multiples
+                .iter()
+                .flat_map(|&multiple| {
+                    let x = multiple * divisor as u128;
+                    let half = x - divisor as u128 / 2;
+                    [x - 1, x, x + 1, half - 1, half, half + 1]
+                })
+                .chain([0, 1, max_value - 1, max_value])
+                .filter(|&x| x <= max_value);
+
+            for x in values {
+                assert_eq!(
+                    divide(&exact, x),
+                    rounded(x, divisor),
+                    "{} / {}",
+                    x,
+                    divisor
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_divisors_without_a_reciprocal() {
+        assert!(Divisor::new(0, 255).is_none());
+        assert!(Divisor::new(3, u128::MAX).is_none());
+        assert!(Divisor::new(u64::MAX, u64::MAX as u128 * u64::MAX as u128).is_none());
+    }
 }
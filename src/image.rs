@@ -1,55 +1,49 @@
 use std::error::Error;
 use std::fmt::{Debug, Formatter};
-use std::fs::File;
-use std::io::BufWriter;
+use std::fs;
 use std::path::Path;
 
 use log::debug;
-use png::{BitDepth, Decoder, Encoder};
 use serde::{Deserialize, Serialize};
 
-use crate::crypt::EncryptedImageData;
-
+use crate::crypt::{EncryptedImageData, Width};
+use crate::image::codec::ImageFormat;
+use crate::image::metadata::AttachedMetadata;
+
+pub mod animation;
+pub mod codec;
+pub mod comparison;
+pub mod container;
+pub mod dithering;
+pub mod integral;
+pub mod metadata;
+pub mod overlay;
 pub mod pixel_operations;
 pub mod rescaling;
+pub mod tiling;
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy)]
 pub enum ColorType {
     Grayscale,
-    Indexed,
     GrayscaleAlpha,
     Rgb,
     Rgba,
 }
 
-impl From<ColorType> for png::ColorType {
-    fn from(value: ColorType) -> Self {
-        match value {
-            ColorType::Grayscale => png::ColorType::Grayscale,
-            ColorType::Indexed => png::ColorType::Indexed,
-            ColorType::GrayscaleAlpha => png::ColorType::GrayscaleAlpha,
-            ColorType::Rgb => png::ColorType::Rgb,
-            ColorType::Rgba => png::ColorType::Rgba,
-        }
-    }
-}
-
-impl From<png::ColorType> for ColorType {
-    fn from(value: png::ColorType) -> Self {
-        match value {
-            png::ColorType::Grayscale => ColorType::Grayscale,
-            png::ColorType::Indexed => ColorType::Indexed,
-            png::ColorType::GrayscaleAlpha => ColorType::GrayscaleAlpha,
-            png::ColorType::Rgb => ColorType::Rgb,
-            png::ColorType::Rgba => ColorType::Rgba,
-        }
+impl ColorType {
+    /// Whether the channel at an index within a pixel is the alpha channel.
+    pub fn is_alpha(&self, channel: usize) -> bool {
+        matches!(
+            (self, channel),
+            (ColorType::GrayscaleAlpha, 1) | (ColorType::Rgba, 3)
+        )
     }
 }
 
 impl From<ColorType> for u16 {
     fn from(value: ColorType) -> Self {
         match value {
-            ColorType::Grayscale | ColorType::Indexed => 1,
+            ColorType::Grayscale => 1,
             ColorType::GrayscaleAlpha => 2,
             ColorType::Rgb => 3,
             ColorType::Rgba => 4,
@@ -57,6 +51,29 @@ impl From<ColorType> for u16 {
     }
 }
 
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy)]
+pub enum BitDepth {
+    Eight,
+    Sixteen,
+}
+
+impl BitDepth {
+    pub fn bits(&self) -> u32 {
+        match self {
+            BitDepth::Eight => 8,
+            BitDepth::Sixteen => 16,
+        }
+    }
+
+    /// The largest value a sample of this depth can hold.
+    pub fn max_value(&self) -> u16 {
+        match self {
+            BitDepth::Eight => u8::MAX as u16,
+            BitDepth::Sixteen => u16::MAX,
+        }
+    }
+}
+
 #[derive(PartialEq, Eq, Serialize, Deserialize, Clone, Copy)]
 pub struct Size {
     pub width: u16,
@@ -64,6 +81,10 @@ pub struct Size {
 }
 
 impl Size {
+    pub fn pixel_count(&self) -> u64 {
+        self.width as u64 * self.height as u64
+    }
+
     pub fn minus_one(&self) -> Self {
         Self {
             width: if self.width > 0 { self.width - 1 } else { 0 },
@@ -78,103 +99,132 @@ impl Debug for Size {
     }
 }
 
-#[derive(PartialEq, Eq, Serialize, Deserialize)]
+#[derive(PartialEq, Eq, Serialize, Deserialize, Clone)]
 pub struct Image<T: Clone> {
     pub data: Vec<T>,
     pub size: Size,
     pub color_type: ColorType,
+    pub bit_depth: BitDepth,
+    pub metadata: AttachedMetadata<T>,
 }
 
 impl<T: Clone> Image<T> {
-    pub fn new(data: Vec<T>, width: u16, height: u16, color_type: ColorType) -> Self {
+    pub fn new(
+        data: Vec<T>,
+        width: u16,
+        height: u16,
+        color_type: ColorType,
+        bit_depth: BitDepth,
+    ) -> Self {
         Self {
             data,
             size: Size { width, height },
             color_type,
+            bit_depth,
+            metadata: AttachedMetadata::None,
         }
     }
 
-    pub fn get_pixel(&self, x: u16, y: u16) -> Option<Vec<&T>> {
-        let (x, y) = (x * self.channel_count(), y * self.channel_count());
-        let index = (x + y * self.size.width) as usize;
-        let mut pixel = Vec::new();
+    pub fn with_metadata(mut self, metadata: AttachedMetadata<T>) -> Self {
+        self.metadata = metadata;
+        self
+    }
 
-        for component in 0..self.channel_count() {
-            pixel.push(self.data.get(index + component as usize));
+    /// The values of the pixel at the given position, or `None` if it lies outside of the image.
+    pub fn get_pixel(&self, x: u16, y: u16) -> Option<Vec<&T>> {
+        if x >= self.size.width || y >= self.size.height {
+            return None;
         }
 
-        pixel.into_iter().collect::<Option<Vec<&T>>>()
+        // the index of the last pixel of a large image does not fit 16 bits
+        let channels = self.channel_count() as usize;
+        let index = (y as usize * self.size.width as usize + x as usize) * channels;
+
+        self.data
+            .get(index..index + channels)
+            .map(|pixel| pixel.iter().collect())
     }
 
     pub fn channel_count(&self) -> u16 {
         self.color_type.into()
     }
+
+    /// Borrow the samples of the image, so operations can read it or a part of it without copying
+    /// them.
+    pub fn view(&self) -> Image<&T> {
+        Image {
+            data: self.data.iter().collect(),
+            size: self.size,
+            color_type: self.color_type,
+            bit_depth: self.bit_depth,
+            metadata: self.metadata.view(),
+        }
+    }
 }
 
-pub type PlaintextImage = Image<u8>;
+pub type PlaintextImage = Image<u16>;
 
 impl PlaintextImage {
     pub fn load(file_path: &Path) -> Result<PlaintextImage, Box<dyn Error>> {
-        let decoder = Decoder::new(File::open(file_path)?);
-        let mut reader = decoder.read_info()?;
-        let mut buffer = vec![0; reader.output_buffer_size()];
-        let info = reader.next_frame(&mut buffer)?;
+        let data = fs::read(file_path)?;
+        let format = ImageFormat::from_magic_bytes(&data)
+            .or_else(|| ImageFormat::from_path(file_path))
+            .ok_or("Unknown image format")?;
 
-        if info.bit_depth != BitDepth::Eight {
-            unimplemented!("Only 8-bit images are supported");
-        }
-
-        let image = Image::new(
-            buffer[..info.buffer_size()].to_vec(),
-            info.width as u16,
-            info.height as u16,
-            info.color_type.into(),
-        );
-        debug!("Loaded {:?} from {:?}", image, file_path);
+        let image = format.codec().decode(&data)?;
+        debug!("Loaded {:?} from {:?} ({:?})", image, file_path, format);
 
         Ok(image)
     }
 
     pub fn save(&self, file_path: &Path) -> Result<(), Box<dyn Error>> {
-        let mut writer = BufWriter::new(File::create(file_path)?);
-        let mut encoder =
-            Encoder::new(&mut writer, self.size.width as u32, self.size.height as u32);
-        encoder.set_color(self.color_type.into());
-        encoder.set_depth(BitDepth::Eight);
-        encoder.set_source_gamma(png::ScaledFloat::from_scaled(45455)); // from https://docs.rs/png/0.17.9/png/#using-the-encoder
-        encoder.set_source_chromaticities(png::SourceChromaticities::new(
-            (0.31270, 0.32900),
-            (0.64000, 0.33000),
-            (0.30000, 0.60000),
-            (0.15000, 0.06000),
-        )); // from https://docs.rs/png/0.17.9/png/#using-the-encoder
-        let mut writer = encoder.write_header()?;
-
-        writer.write_image_data(&self.data)?;
-        debug!("Wrote {:?} to {:?}", self, file_path);
+        let format = ImageFormat::from_path(file_path).ok_or("Unknown image format")?;
+
+        fs::write(file_path, format.codec().encode(self)?)?;
+        debug!("Wrote {:?} to {:?} ({:?})", self, file_path, format);
 
         Ok(())
     }
+
+    /// The samples of this image reduced to 8 bits, for formats that cannot store more.
+    pub fn eight_bit_data(&self) -> Vec<u8> {
+        match self.bit_depth {
+            BitDepth::Eight => self.data.iter().map(|&x| x as u8).collect(),
+            BitDepth::Sixteen => self.data.iter().map(|&x| (x >> 8) as u8).collect(),
+        }
+    }
 }
 
 impl Debug for PlaintextImage {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "Image ({:?}, {:?}), data: {:?}",
-            self.size, self.color_type, self.data
+            "Image ({:?}, {:?}, {:?}), data: {:?}",
+            self.size, self.color_type, self.bit_depth, self.data
         )
     }
 }
 
 pub type EncryptedImage = Image<EncryptedImageData>;
+/// An encrypted image borrowing its samples, see [`Image::view`].
+pub type EncryptedImageView<'a> = Image<&'a EncryptedImageData>;
+
+impl EncryptedImage {
+    /// The width the samples are encrypted with, or `None` for an empty image.
+    pub fn width(&self) -> Option<Width> {
+        self.data.first().map(Width::of)
+    }
+}
 
 impl Debug for EncryptedImage {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "Encrypted image ({:?}, {:?})",
-            self.size, self.color_type
+            "Encrypted image ({:?}, {:?}, {:?}, {:?})",
+            self.size,
+            self.color_type,
+            self.bit_depth,
+            self.width()
         )
     }
 }
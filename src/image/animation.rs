@@ -0,0 +1,141 @@
+use std::error::Error;
+use std::fmt::{Debug, Formatter};
+use std::fs;
+use std::path::Path;
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::crypt::EncryptedImageData;
+use crate::image::codec::png::PngCodec;
+use crate::image::codec::ImageFormat;
+use crate::image::{Image, PlaintextImage};
+
+/// How long a frame is shown, in seconds.
+///
+/// A denominator of 0 is treated as 100.
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize, Clone, Copy)]
+pub struct Delay {
+    pub numerator: u16,
+    pub denominator: u16,
+}
+
+#[derive(PartialEq, Eq, Serialize, Deserialize, Clone)]
+pub struct Frame<T: Clone> {
+    pub image: Image<T>,
+    pub delay: Delay,
+}
+
+/// A sequence of frames of the same size and format.
+#[derive(PartialEq, Eq, Serialize, Deserialize, Clone)]
+pub struct Animation<T: Clone> {
+    pub frames: Vec<Frame<T>>,
+    /// How many times the animation is played, 0 meaning indefinitely.
+    pub plays: u32,
+}
+
+impl<T: Clone> Animation<T> {
+    pub fn is_animated(&self) -> bool {
+        self.frames.len() > 1
+    }
+
+    /// Apply an operation to every frame, keeping the timing.
+    pub fn map_frames<U: Clone>(
+        &self,
+        mut operation: impl FnMut(&Image<T>) -> Image<U>,
+    ) -> Animation<U> {
+        self.try_map_frames(|image| Some(operation(image))).unwrap()
+    }
+
+    /// Like [`Animation::map_frames`], but returns `None` if the operation fails on any frame.
+    pub fn try_map_frames<U: Clone>(
+        &self,
+        mut operation: impl FnMut(&Image<T>) -> Option<Image<U>>,
+    ) -> Option<Animation<U>> {
+        Some(Animation {
+            frames: self
+                .frames
+                .iter()
+                .map(|frame| {
+                    Some(Frame {
+                        image: operation(&frame.image)?,
+                        delay: frame.delay,
+                    })
+                })
+                .collect::<Option<Vec<Frame<U>>>>()?,
+            plays: self.plays,
+        })
+    }
+
+    /// Take the first frame, discarding all others.
+    pub fn into_first_frame(self) -> Option<Image<T>> {
+        self.frames.into_iter().next().map(|frame| frame.image)
+    }
+}
+
+impl<T: Clone> From<Image<T>> for Animation<T> {
+    fn from(value: Image<T>) -> Self {
+        Self {
+            frames: vec![Frame {
+                image: value,
+                delay: Delay::default(),
+            }],
+            plays: 0,
+        }
+    }
+}
+
+impl<T: Clone> Debug for Animation<T>
+where
+    Image<T>: Debug,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Animation ({} frames, {} plays), first frame: {:?}",
+            self.frames.len(),
+            self.plays,
+            self.frames.first().map(|frame| &frame.image)
+        )
+    }
+}
+
+pub type PlaintextAnimation = Animation<u16>;
+
+impl PlaintextAnimation {
+    /// Load all frames of an animated png, or any other image as a single frame.
+    pub fn load(file_path: &Path) -> Result<PlaintextAnimation, Box<dyn Error>> {
+        let data = fs::read(file_path)?;
+
+        if ImageFormat::from_magic_bytes(&data) != Some(ImageFormat::Png) {
+            return Ok(PlaintextImage::load(file_path)?.into());
+        }
+
+        let animation = PngCodec.decode_animation(&data)?;
+        debug!("Loaded {:?} from {:?}", animation, file_path);
+
+        Ok(animation)
+    }
+
+    /// Save as an animated png, or as a regular image if there is only one frame.
+    pub fn save(&self, file_path: &Path) -> Result<(), Box<dyn Error>> {
+        if !self.is_animated() {
+            return self
+                .frames
+                .first()
+                .ok_or("Animation has no frames")?
+                .image
+                .save(file_path);
+        }
+        if ImageFormat::from_path(file_path) != Some(ImageFormat::Png) {
+            return Err("Animations can only be saved as png".into());
+        }
+
+        fs::write(file_path, PngCodec.encode_animation(self)?)?;
+        debug!("Wrote {:?} to {:?}", self, file_path);
+
+        Ok(())
+    }
+}
+
+pub type EncryptedAnimation = Animation<EncryptedImageData>;
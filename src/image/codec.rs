@@ -0,0 +1,81 @@
+use std::error::Error;
+use std::path::Path;
+
+use crate::image::PlaintextImage;
+
+pub mod bmp;
+pub mod jpeg;
+pub mod png;
+pub mod pnm;
+pub mod tiff;
+
+/// Conversion between the contents of an image file and a [`PlaintextImage`].
+pub trait ImageCodec {
+    /// Decode the contents of an image file.
+    fn decode(&self, data: &[u8]) -> Result<PlaintextImage, Box<dyn Error>>;
+
+    /// Encode an image to the contents of an image file.
+    fn encode(&self, image: &PlaintextImage) -> Result<Vec<u8>, Box<dyn Error>>;
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ImageFormat {
+    Png,
+    Pnm,
+    Bmp,
+    Jpeg,
+    Tiff,
+}
+
+impl ImageFormat {
+    /// Guess the format of a file from its extension.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+
+        match extension.as_str() {
+            "png" => Some(ImageFormat::Png),
+            "pbm" | "pgm" | "ppm" | "pnm" | "pam" => Some(ImageFormat::Pnm),
+            "bmp" | "dib" => Some(ImageFormat::Bmp),
+            "jpg" | "jpeg" => Some(ImageFormat::Jpeg),
+            "tif" | "tiff" => Some(ImageFormat::Tiff),
+            _ => None,
+        }
+    }
+
+    /// Detect the format of a file from the magic bytes at its start.
+    pub fn from_magic_bytes(data: &[u8]) -> Option<Self> {
+        match data {
+            [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a, ..] => Some(ImageFormat::Png),
+            [b'P', b'1'..=b'7', whitespace, ..] if whitespace.is_ascii_whitespace() => {
+                Some(ImageFormat::Pnm)
+            }
+            [b'B', b'M', ..] => Some(ImageFormat::Bmp),
+            [0xff, 0xd8, 0xff, ..] => Some(ImageFormat::Jpeg),
+            [b'I', b'I', 0x2a, 0x00, ..] | [b'M', b'M', 0x00, 0x2a, ..] => Some(ImageFormat::Tiff),
+            _ => None,
+        }
+    }
+
+    pub fn codec(&self) -> &'static dyn ImageCodec {
+        match self {
+            ImageFormat::Png => &self::png::PngCodec,
+            ImageFormat::Pnm => &self::pnm::PnmCodec,
+            ImageFormat::Bmp => &self::bmp::BmpCodec,
+            ImageFormat::Jpeg => &self::jpeg::JpegCodec,
+            ImageFormat::Tiff => &self::tiff::TiffCodec,
+        }
+    }
+}
+
+/// Convert interleaved grayscale and alpha samples to rgba for formats without a grayscale alpha
+/// colour type.
+fn gray_alpha_to_rgba<T: Copy>(data: &[T]) -> Vec<T> {
+    data.chunks_exact(2)
+        .flat_map(|pixel| [pixel[0], pixel[0], pixel[0], pixel[1]])
+        .collect()
+}
+
+/// Convert a dimension read from a file to the size type used by images.
+fn dimension(value: u32) -> Result<u16, Box<dyn Error>> {
+    u16::try_from(value).map_err(|_| format!("Image dimension {} is too large", value).into())
+}
@@ -0,0 +1,329 @@
+use std::error::Error;
+
+use crate::image::codec::{dimension, gray_alpha_to_rgba, ImageCodec};
+use crate::image::{BitDepth, ColorType, Image, PlaintextImage};
+
+const FILE_HEADER_SIZE: usize = 14;
+const INFO_HEADER_SIZE: usize = 40;
+const V4_HEADER_SIZE: usize = 108;
+const COMPRESSION_RGB: u32 = 0;
+const COMPRESSION_BITFIELDS: u32 = 3;
+const COMPRESSION_ALPHA_BITFIELDS: u32 = 6;
+
+/// Codec for uncompressed windows bitmaps.
+///
+/// Paletted bitmaps are expanded to rgb. Images are written as 8-bit paletted bitmaps if they are
+/// grayscale, as 24-bit bitmaps if they are rgb and as 32-bit bitmaps with an alpha mask otherwise.
+/// Bitmaps cannot hold 16-bit samples, so those are reduced to 8 bits.
+pub struct BmpCodec;
+
+impl ImageCodec for BmpCodec {
+    fn decode(&self, data: &[u8]) -> Result<PlaintextImage, Box<dyn Error>> {
+        let pixel_offset = read_u32(data, 10)? as usize;
+        let header_size = read_u32(data, FILE_HEADER_SIZE)? as usize;
+        // os/2 bitmaps have a smaller header with 16-bit dimensions and 3-byte palette entries
+        let is_core = header_size == 12;
+        let (width, height, bits_per_pixel, compression) = if is_core {
+            (
+                read_u16(data, 18)? as i32,
+                read_u16(data, 20)? as i32,
+                read_u16(data, 24)?,
+                COMPRESSION_RGB,
+            )
+        } else {
+            (
+                read_u32(data, 18)? as i32,
+                read_u32(data, 22)? as i32,
+                read_u16(data, 28)?,
+                read_u32(data, 30)?,
+            )
+        };
+        // negative heights mean the rows are stored top to bottom
+        let top_down = height < 0;
+        let (width, height) = (
+            dimension(width.unsigned_abs())?,
+            dimension(height.unsigned_abs())?,
+        );
+
+        let masks = match compression {
+            COMPRESSION_RGB => None,
+            COMPRESSION_BITFIELDS | COMPRESSION_ALPHA_BITFIELDS => {
+                let has_alpha = compression == COMPRESSION_ALPHA_BITFIELDS
+                    || header_size > INFO_HEADER_SIZE + 12;
+                Some([
+                    read_u32(data, FILE_HEADER_SIZE + INFO_HEADER_SIZE)?,
+                    read_u32(data, FILE_HEADER_SIZE + INFO_HEADER_SIZE + 4)?,
+                    read_u32(data, FILE_HEADER_SIZE + INFO_HEADER_SIZE + 8)?,
+                    if has_alpha {
+                        read_u32(data, FILE_HEADER_SIZE + INFO_HEADER_SIZE + 12)?
+                    } else {
+                        0
+                    },
+                ])
+            }
+            _ => return Err("Compressed bitmaps are not supported".into()),
+        };
+        let masks = masks.unwrap_or(match bits_per_pixel {
+            16 => [0x7c00, 0x03e0, 0x001f, 0],
+            _ => [0x00ff0000, 0x0000ff00, 0x000000ff, 0],
+        });
+
+        let palette = if bits_per_pixel <= 8 {
+            let entry_size = if is_core { 3 } else { 4 };
+            let colors_used = if is_core {
+                0
+            } else {
+                read_u32(data, 46)? as usize
+            };
+            let entries = if colors_used == 0 {
+                1 << bits_per_pixel
+            } else {
+                colors_used
+            };
+            // bitfield masks without a v4 header are stored between the header and the palette
+            let start = FILE_HEADER_SIZE
+                + header_size
+                + if header_size == INFO_HEADER_SIZE && compression != COMPRESSION_RGB {
+                    12
+                } else {
+                    0
+                };
+
+            (0..entries)
+                .map(|i| {
+                    let entry = data
+                        .get(start + i * entry_size..start + i * entry_size + 3)
+                        .ok_or("Truncated bitmap palette")?;
+                    Ok([entry[2], entry[1], entry[0]])
+                })
+                .collect::<Result<Vec<[u8; 3]>, Box<dyn Error>>>()?
+        } else {
+            Vec::new()
+        };
+
+        let has_alpha = masks[3] != 0;
+        let channels = if has_alpha { 4 } else { 3 };
+        let row_size = (width as usize * bits_per_pixel as usize).div_ceil(32) * 4;
+        // the header is not trusted with the size of the image before the data is known to hold it
+        if pixel_offset + row_size * height as usize > data.len() {
+            return Err("Truncated bitmap data".into());
+        }
+        let mut pixels = Vec::with_capacity(width as usize * height as usize * channels);
+
+        for y in 0..height as usize {
+            let row = if top_down { y } else { height as usize - 1 - y };
+            let row_start = pixel_offset + row * row_size;
+            let row = data
+                .get(row_start..row_start + row_size)
+                .ok_or("Truncated bitmap data")?;
+
+            for x in 0..width as usize {
+                match bits_per_pixel {
+                    1 | 2 | 4 | 8 => {
+                        let bit = x * bits_per_pixel as usize;
+                        let index = (row[bit / 8] >> (8 - bits_per_pixel as usize - bit % 8))
+                            & ((1 << bits_per_pixel) - 1) as u8;
+                        let color = palette
+                            .get(index as usize)
+                            .ok_or("Bitmap palette index out of range")?;
+                        pixels.extend(color.iter().map(|&x| x as u16));
+                    }
+                    16 | 24 | 32 => {
+                        let bytes = bits_per_pixel as usize / 8;
+                        let value = row[x * bytes..(x + 1) * bytes]
+                            .iter()
+                            .rev()
+                            .fold(0_u32, |value, &byte| (value << 8) | byte as u32);
+                        pixels.extend(masks[..channels].iter().map(|&mask| masked(value, mask)));
+                    }
+                    _ => return Err(format!("Unsupported bitmap depth {}", bits_per_pixel).into()),
+                }
+            }
+        }
+
+        Ok(Image::new(
+            pixels,
+            width,
+            height,
+            if has_alpha {
+                ColorType::Rgba
+            } else {
+                ColorType::Rgb
+            },
+            BitDepth::Eight,
+        ))
+    }
+
+    fn encode(&self, image: &PlaintextImage) -> Result<Vec<u8>, Box<dyn Error>> {
+        let (width, height) = (image.size.width as usize, image.size.height as usize);
+        let (data, channels) = match image.color_type {
+            ColorType::GrayscaleAlpha => (gray_alpha_to_rgba(&image.eight_bit_data()), 4),
+            _ => (image.eight_bit_data(), image.channel_count() as usize),
+        };
+        let (header_size, bits_per_pixel, palette_size) = match channels {
+            1 => (INFO_HEADER_SIZE, 8, 256 * 4),
+            3 => (INFO_HEADER_SIZE, 24, 0),
+            _ => (V4_HEADER_SIZE, 32, 0),
+        };
+        let row_size = (width * bits_per_pixel).div_ceil(32) * 4;
+        let pixel_offset = FILE_HEADER_SIZE + header_size + palette_size;
+        let file_size = pixel_offset + row_size * height;
+
+        let mut buffer = Vec::with_capacity(file_size);
+        // file header
+        buffer.extend(b"BM");
+        buffer.extend((file_size as u32).to_le_bytes());
+        buffer.extend([0; 4]);
+        buffer.extend((pixel_offset as u32).to_le_bytes());
+        // info header
+        buffer.extend((header_size as u32).to_le_bytes());
+        buffer.extend((width as i32).to_le_bytes());
+        buffer.extend((height as i32).to_le_bytes());
+        buffer.extend(1_u16.to_le_bytes());
+        buffer.extend((bits_per_pixel as u16).to_le_bytes());
+        buffer.extend(
+            if channels == 4 {
+                COMPRESSION_BITFIELDS
+            } else {
+                COMPRESSION_RGB
+            }
+            .to_le_bytes(),
+        );
+        buffer.extend(((row_size * height) as u32).to_le_bytes());
+        // 72 dpi in pixels per metre
+        buffer.extend(2835_u32.to_le_bytes());
+        buffer.extend(2835_u32.to_le_bytes());
+        buffer.extend(((palette_size / 4) as u32).to_le_bytes());
+        buffer.extend(0_u32.to_le_bytes());
+        if header_size == V4_HEADER_SIZE {
+            // channel masks for bgra byte order
+            for mask in [0x00ff0000_u32, 0x0000ff00, 0x000000ff, 0xff000000] {
+                buffer.extend(mask.to_le_bytes());
+            }
+            // srgb colour space, the remaining endpoints and gamma values are unused
+            buffer.extend(b"BGRs");
+            buffer.extend([0; 48]);
+        }
+        for gray in 0..palette_size / 4 {
+            buffer.extend([gray as u8, gray as u8, gray as u8, 0]);
+        }
+
+        // rows are stored bottom to top, with each pixel in bgr(a) order
+        for row in data.chunks_exact(width * channels).rev() {
+            let row_start = buffer.len();
+            for pixel in row.chunks_exact(channels) {
+                match channels {
+                    1 => buffer.push(pixel[0]),
+                    3 => buffer.extend([pixel[2], pixel[1], pixel[0]]),
+                    _ => buffer.extend([pixel[2], pixel[1], pixel[0], pixel[3]]),
+                }
+            }
+            buffer.resize(row_start + row_size, 0);
+        }
+
+        Ok(buffer)
+    }
+}
+
+/// Extract the value selected by a channel mask and scale it to 8 bits.
+fn masked(value: u32, mask: u32) -> u16 {
+    if mask == 0 {
+        return 0;
+    }
+
+    let max_value = mask >> mask.trailing_zeros();
+    let value = (value & mask) >> mask.trailing_zeros();
+
+    ((value as u64 * 255 + max_value as u64 / 2) / max_value as u64) as u16
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, Box<dyn Error>> {
+    let bytes = data
+        .get(offset..offset + 2)
+        .ok_or("Truncated bitmap header")?;
+
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, Box<dyn Error>> {
+    let bytes = data
+        .get(offset..offset + 4)
+        .ok_or("Truncated bitmap header")?;
+
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(image: &PlaintextImage) -> PlaintextImage {
+        BmpCodec.decode(&BmpCodec.encode(image).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn round_trips_rgb_and_rgba() {
+        // a width of three pixels needs row padding
+        let rgb = Image::new(
+            (0..18).map(|x| x * 14).collect(),
+            3,
+            2,
+            ColorType::Rgb,
+            BitDepth::Eight,
+        );
+        assert_eq!(round_trip(&rgb), rgb);
+
+        let rgba = Image::new(
+            vec![1, 2, 3, 4, 250, 251, 252, 0],
+            1,
+            2,
+            ColorType::Rgba,
+            BitDepth::Eight,
+        );
+        assert_eq!(round_trip(&rgba), rgba);
+    }
+
+    #[test]
+    fn expands_grayscale_to_rgb() {
+        let gray = Image::new(
+            vec![0, 100, 200, 255],
+            2,
+            2,
+            ColorType::Grayscale,
+            BitDepth::Eight,
+        );
+        let decoded = round_trip(&gray);
+
+        assert_eq!(decoded.color_type, ColorType::Rgb);
+        assert_eq!(
+            decoded.data,
+            [0, 0, 0, 100, 100, 100, 200, 200, 200, 255, 255, 255]
+        );
+    }
+
+    #[test]
+    fn reduces_sixteen_bit_samples() {
+        let rgb = Image::new(vec![0, 257, 65535], 1, 1, ColorType::Rgb, BitDepth::Sixteen);
+        let decoded = round_trip(&rgb);
+
+        assert_eq!(decoded.bit_depth, BitDepth::Eight);
+        assert_eq!(decoded.data, [0, 1, 255]);
+    }
+
+    #[test]
+    fn rejects_truncated_data() {
+        let rgb = Image::new(vec![1, 2, 3], 1, 1, ColorType::Rgb, BitDepth::Eight);
+        let encoded = BmpCodec.encode(&rgb).unwrap();
+
+        assert!(BmpCodec.decode(&encoded[..encoded.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn rejects_sizes_larger_than_the_data() {
+        let rgb = Image::new(vec![1, 2, 3], 1, 1, ColorType::Rgb, BitDepth::Eight);
+        let mut encoded = BmpCodec.encode(&rgb).unwrap();
+        encoded[18..26].copy_from_slice(&[0xff, 0xff, 0, 0, 0xff, 0xff, 0, 0]);
+
+        assert!(BmpCodec.decode(&encoded).is_err());
+    }
+}
@@ -0,0 +1,172 @@
+use std::error::Error;
+
+use jpeg_decoder::{Decoder, PixelFormat};
+use jpeg_encoder::Encoder;
+
+use crate::image::codec::ImageCodec;
+use crate::image::{BitDepth, ColorType, Image, PlaintextImage};
+
+const QUALITY: u8 = 90;
+
+/// Codec for jpeg images.
+///
+/// CMYK images are converted to rgb. Jpeg cannot store alpha channels or 16-bit samples, so alpha
+/// is dropped and samples are reduced to 8 bits when encoding. Lossless images with a precision
+/// other than 8 bits are decoded with 16-bit samples.
+pub struct JpegCodec;
+
+impl ImageCodec for JpegCodec {
+    fn decode(&self, data: &[u8]) -> Result<PlaintextImage, Box<dyn Error>> {
+        let mut decoder = Decoder::new(data);
+        let pixels = decoder.decode()?;
+        let info = decoder.info().ok_or("Missing jpeg image info")?;
+
+        let precision = frame_precision(data).ok_or("Missing jpeg frame header")?;
+
+        let (data, color_type, bit_depth) = match (info.pixel_format, precision) {
+            (PixelFormat::L8, 8) => (
+                pixels.iter().map(|&x| x as u16).collect(),
+                ColorType::Grayscale,
+                BitDepth::Eight,
+            ),
+            (PixelFormat::RGB24, 8) => (
+                pixels.iter().map(|&x| x as u16).collect(),
+                ColorType::Rgb,
+                BitDepth::Eight,
+            ),
+            (PixelFormat::CMYK32, 8) => (
+                pixels
+                    .chunks_exact(4)
+                    .flat_map(|pixel| {
+                        let k = 255 - pixel[3] as u16;
+                        [0, 1, 2].map(|i| (255 - pixel[i] as u16) * k / 255)
+                    })
+                    .collect(),
+                ColorType::Rgb,
+                BitDepth::Eight,
+            ),
+            (PixelFormat::L8 | PixelFormat::L16, _) => (
+                rescale(&pixels, precision),
+                ColorType::Grayscale,
+                BitDepth::Sixteen,
+            ),
+            (PixelFormat::RGB24, _) => (
+                rescale(&pixels, precision),
+                ColorType::Rgb,
+                BitDepth::Sixteen,
+            ),
+            (pixel_format, _) => {
+                return Err(format!(
+                    "Unsupported {}-bit jpeg pixel format {:?}",
+                    precision, pixel_format
+                )
+                .into())
+            }
+        };
+
+        Ok(Image::new(
+            data,
+            info.width,
+            info.height,
+            color_type,
+            bit_depth,
+        ))
+    }
+
+    fn encode(&self, image: &PlaintextImage) -> Result<Vec<u8>, Box<dyn Error>> {
+        let data = image.eight_bit_data();
+        let (data, color_type) = match image.color_type {
+            ColorType::Grayscale => (data, jpeg_encoder::ColorType::Luma),
+            ColorType::GrayscaleAlpha => (
+                data.chunks_exact(2).map(|pixel| pixel[0]).collect(),
+                jpeg_encoder::ColorType::Luma,
+            ),
+            ColorType::Rgb => (data, jpeg_encoder::ColorType::Rgb),
+            ColorType::Rgba => (data, jpeg_encoder::ColorType::Rgba),
+        };
+
+        let mut buffer = Vec::new();
+        Encoder::new(&mut buffer, QUALITY).encode(
+            &data,
+            image.size.width,
+            image.size.height,
+            color_type,
+        )?;
+
+        Ok(buffer)
+    }
+}
+
+/// Read the sample precision from the frame header, which the decoder does not report.
+fn frame_precision(data: &[u8]) -> Option<u8> {
+    // skip the start of image marker
+    let mut position = 2;
+
+    loop {
+        if *data.get(position)? != 0xff {
+            return None;
+        }
+        match *data.get(position + 1)? {
+            // markers may be preceded by any number of fill bytes
+            0xff => {
+                position += 1;
+                continue;
+            }
+            // start of frame markers, apart from huffman and arithmetic coding tables
+            marker @ 0xc0..=0xcf if !matches!(marker, 0xc4 | 0xc8 | 0xcc) => {
+                return data.get(position + 4).copied();
+            }
+            _ => {}
+        }
+
+        let length = u16::from_be_bytes([*data.get(position + 2)?, *data.get(position + 3)?]);
+        position += 2 + length as usize;
+    }
+}
+
+/// Scale samples of a lossless image, which are decoded to native endian 16-bit values in the
+/// range of their precision, to the full 16-bit range.
+fn rescale(pixels: &[u8], precision: u8) -> Vec<u16> {
+    let max_value = (1_u32 << precision) - 1;
+
+    pixels
+        .chunks_exact(2)
+        .map(|x| {
+            let value = u16::from_ne_bytes([x[0], x[1]]) as u32;
+            ((value.min(max_value) * u16::MAX as u32 + max_value / 2) / max_value) as u16
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_precision_after_other_segments() {
+        let image = Image::new(
+            vec![0, 100, 200, 255],
+            2,
+            2,
+            ColorType::Grayscale,
+            BitDepth::Eight,
+        );
+        let encoded = JpegCodec.encode(&image).unwrap();
+
+        assert_eq!(frame_precision(&encoded), Some(8));
+        assert_eq!(
+            JpegCodec.decode(&encoded).unwrap().bit_depth,
+            BitDepth::Eight
+        );
+    }
+
+    #[test]
+    fn rescales_lossless_samples() {
+        let pixels: Vec<u8> = [0_u16, 2048, 4095]
+            .iter()
+            .flat_map(|x| x.to_ne_bytes())
+            .collect();
+
+        assert_eq!(rescale(&pixels, 12), [0, 32776, 65535]);
+    }
+}
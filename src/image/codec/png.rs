@@ -0,0 +1,475 @@
+use std::error::Error;
+use std::io::Write;
+
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use log::warn;
+use png::chunk::ChunkType;
+use png::{
+    BlendOp, Decoder, DisposeOp, Encoder, FrameControl, Info, OutputInfo, ScaledFloat,
+    SourceChromaticities, SrgbRenderingIntent, Transformations, Unit, Writer,
+};
+
+use crate::image::animation::{Animation, Delay, Frame, PlaintextAnimation};
+use crate::image::codec::{dimension, ImageCodec};
+use crate::image::metadata::{AttachedMetadata, Metadata, PixelDimensions, Text, TextKind};
+use crate::image::{BitDepth, ColorType, Image, PlaintextImage};
+
+pub struct PngCodec;
+
+impl ImageCodec for PngCodec {
+    fn decode(&self, data: &[u8]) -> Result<PlaintextImage, Box<dyn Error>> {
+        let mut decoder = Decoder::new(data);
+        // expand palettes to rgb(a) and depths below 8 bits to 8 bits so operations always work on
+        // actual colour values
+        decoder.set_transformations(Transformations::EXPAND);
+        let mut reader = decoder.read_info()?;
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buffer)?;
+
+        Ok(frame_image(&buffer, &info)?.with_metadata(read_metadata(reader.info())?))
+    }
+
+    fn encode(&self, image: &PlaintextImage) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut buffer = Vec::new();
+        let mut writer = encoder(image, &mut buffer).write_header()?;
+
+        write_metadata_chunks(image, &mut writer)?;
+        writer.write_image_data(&sample_bytes(image))?;
+        writer.finish()?;
+
+        Ok(buffer)
+    }
+}
+
+impl PngCodec {
+    /// Decode all frames of an animated png, composited onto the full canvas.
+    ///
+    /// Images that are not animated are decoded as a single frame.
+    pub fn decode_animation(&self, data: &[u8]) -> Result<PlaintextAnimation, Box<dyn Error>> {
+        let mut decoder = Decoder::new(data);
+        decoder.set_transformations(Transformations::EXPAND);
+        let mut reader = decoder.read_info()?;
+        let Some(animation_control) = reader.info().animation_control else {
+            return Ok(self.decode(data)?.into());
+        };
+        let (width, height) = reader.info().size();
+        let (width, height) = (dimension(width)?, dimension(height)?);
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        let mut canvas: Option<PlaintextImage> = None;
+        // the frame count of the header is not trusted, frames are only kept once decoded
+        let mut frames = Vec::new();
+
+        // without a frame control chunk before the image data, the default image is not part of
+        // the animation
+        if reader.info().frame_control.is_none() {
+            reader.next_frame(&mut buffer)?;
+        }
+
+        for _ in 0..animation_control.num_frames {
+            let info = reader.next_frame(&mut buffer)?;
+            let control = reader.info().frame_control.ok_or("Missing frame control")?;
+            let image = frame_image(&buffer, &info)?;
+            let canvas = canvas.get_or_insert_with(|| {
+                let length = width as usize * height as usize * image.channel_count() as usize;
+
+                Image::new(
+                    vec![0; length],
+                    width,
+                    height,
+                    image.color_type,
+                    image.bit_depth,
+                )
+            });
+            let previous = (control.dispose_op == DisposeOp::Previous).then(|| canvas.data.clone());
+
+            blend(canvas, &image, &control);
+            frames.push(Frame {
+                image: canvas.clone(),
+                delay: Delay {
+                    numerator: control.delay_num,
+                    denominator: control.delay_den,
+                },
+            });
+
+            match control.dispose_op {
+                DisposeOp::None => {}
+                DisposeOp::Background => clear(canvas, &control),
+                // the canvas is cleared before the first frame, so this also covers disposing the
+                // first frame to the previous state
+                DisposeOp::Previous => canvas.data = previous.unwrap_or_default(),
+            }
+        }
+
+        let metadata = read_metadata(reader.info())?;
+        for frame in &mut frames {
+            frame.image.metadata = metadata.clone();
+        }
+
+        Ok(Animation {
+            frames,
+            plays: animation_control.num_plays,
+        })
+    }
+
+    /// Encode an animated png with every frame covering the full canvas.
+    pub fn encode_animation(
+        &self,
+        animation: &PlaintextAnimation,
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        let first_frame = &animation
+            .frames
+            .first()
+            .ok_or("Animation has no frames")?
+            .image;
+        let mut buffer = Vec::new();
+        let mut encoder = encoder(first_frame, &mut buffer);
+        encoder.set_animated(animation.frames.len() as u32, animation.plays)?;
+        let mut writer = encoder.write_header()?;
+        write_metadata_chunks(first_frame, &mut writer)?;
+
+        for frame in &animation.frames {
+            if (
+                frame.image.size,
+                frame.image.color_type,
+                frame.image.bit_depth,
+            ) != (
+                first_frame.size,
+                first_frame.color_type,
+                first_frame.bit_depth,
+            ) {
+                return Err("All frames of an animation must have the same format".into());
+            }
+
+            writer.set_frame_delay(frame.delay.numerator, frame.delay.denominator)?;
+            writer.write_image_data(&sample_bytes(&frame.image))?;
+        }
+        writer.finish()?;
+
+        Ok(buffer)
+    }
+}
+
+fn encoder<'a>(image: &PlaintextImage, buffer: &'a mut Vec<u8>) -> Encoder<'a, &'a mut Vec<u8>> {
+    let mut encoder = Encoder::new(buffer, image.size.width as u32, image.size.height as u32);
+    encoder.set_color(image.color_type.into());
+    encoder.set_depth(image.bit_depth.into());
+
+    match &image.metadata {
+        AttachedMetadata::Plaintext(metadata) => {
+            if let Some(intent) = metadata.srgb_intent.and_then(srgb_intent) {
+                // also writes the matching gamma and chromaticities
+                encoder.set_srgb(intent);
+            }
+            if let Some(gamma) = metadata.gamma {
+                encoder.set_source_gamma(ScaledFloat::from_scaled(gamma));
+            }
+            if let Some([white, red, green, blue]) = metadata.chromaticities {
+                encoder.set_source_chromaticities(SourceChromaticities {
+                    white: scaled_pair(white),
+                    red: scaled_pair(red),
+                    green: scaled_pair(green),
+                    blue: scaled_pair(blue),
+                });
+            }
+            for text in &metadata.text {
+                let result = match text.kind {
+                    TextKind::Latin1 => {
+                        encoder.add_text_chunk(text.keyword.clone(), text.text.clone())
+                    }
+                    TextKind::CompressedLatin1 => {
+                        encoder.add_ztxt_chunk(text.keyword.clone(), text.text.clone())
+                    }
+                    TextKind::Utf8 => {
+                        encoder.add_itxt_chunk(text.keyword.clone(), text.text.clone())
+                    }
+                };
+                if let Err(error) = result {
+                    warn!("Skipping text chunk {:?}: {}", text.keyword, error);
+                }
+            }
+        }
+        // without any known colour information, assume srgb
+        AttachedMetadata::None | AttachedMetadata::Encrypted(_) => {
+            encoder.set_source_gamma(ScaledFloat::from_scaled(45455)); // from https://docs.rs/png/0.17.9/png/#using-the-encoder
+            encoder.set_source_chromaticities(SourceChromaticities::new(
+                (0.31270, 0.32900),
+                (0.64000, 0.33000),
+                (0.30000, 0.60000),
+                (0.15000, 0.06000),
+            )); // from https://docs.rs/png/0.17.9/png/#using-the-encoder
+        }
+    }
+
+    encoder
+}
+
+/// Collect the ancillary chunks of a decoded image.
+fn read_metadata(info: &Info) -> Result<AttachedMetadata<u16>, Box<dyn Error>> {
+    let mut text = Vec::new();
+    text.extend(info.uncompressed_latin1_text.iter().map(|chunk| Text {
+        keyword: chunk.keyword.clone(),
+        text: chunk.text.clone(),
+        kind: TextKind::Latin1,
+    }));
+    for chunk in &info.compressed_latin1_text {
+        text.push(Text {
+            keyword: chunk.keyword.clone(),
+            text: chunk.get_text()?,
+            kind: TextKind::CompressedLatin1,
+        });
+    }
+    for chunk in &info.utf8_text {
+        text.push(Text {
+            keyword: chunk.keyword.clone(),
+            text: chunk.get_text()?,
+            kind: TextKind::Utf8,
+        });
+    }
+
+    Ok(AttachedMetadata::Plaintext(Metadata {
+        gamma: info.gama_chunk.map(ScaledFloat::into_scaled),
+        chromaticities: info.chrm_chunk.map(|chromaticities| {
+            [
+                chromaticities.white,
+                chromaticities.red,
+                chromaticities.green,
+                chromaticities.blue,
+            ]
+            .map(|(x, y)| (x.into_scaled(), y.into_scaled()))
+        }),
+        srgb_intent: info.srgb.map(|intent| intent as u8),
+        icc_profile: info.icc_profile.as_ref().map(|profile| profile.to_vec()),
+        pixel_dimensions: info.pixel_dims.map(|dimensions| PixelDimensions {
+            x: dimensions.xppu,
+            y: dimensions.yppu,
+            per_metre: dimensions.unit == Unit::Meter,
+        }),
+        text,
+    }))
+}
+
+/// Write the ancillary chunks the encoder has no support for.
+fn write_metadata_chunks(
+    image: &PlaintextImage,
+    writer: &mut Writer<&mut Vec<u8>>,
+) -> Result<(), Box<dyn Error>> {
+    let AttachedMetadata::Plaintext(metadata) = &image.metadata else {
+        return Ok(());
+    };
+
+    // an srgb chunk must not be combined with a colour profile, and a profile only applies if it
+    // describes the colour space of the image, which grayscale conversion changes
+    if let (Some(profile), None) = (&metadata.icc_profile, metadata.srgb_intent) {
+        let profile_color_space = profile.get(16..20);
+        let image_color_space = match image.color_type {
+            ColorType::Grayscale | ColorType::GrayscaleAlpha => b"GRAY",
+            ColorType::Rgb | ColorType::Rgba => b"RGB ",
+        };
+
+        if profile_color_space == Some(image_color_space) {
+            let mut data = b"ICC profile\0\0".to_vec();
+            let mut compressor = ZlibEncoder::new(&mut data, Compression::default());
+            compressor.write_all(profile)?;
+            compressor.finish()?;
+
+            writer.write_chunk(ChunkType(*b"iCCP"), &data)?;
+        }
+    }
+    if let Some(dimensions) = metadata.pixel_dimensions {
+        let mut data = Vec::with_capacity(9);
+        data.extend(dimensions.x.to_be_bytes());
+        data.extend(dimensions.y.to_be_bytes());
+        data.push(dimensions.per_metre as u8);
+
+        writer.write_chunk(ChunkType(*b"pHYs"), &data)?;
+    }
+
+    Ok(())
+}
+
+fn scaled_pair((x, y): (u32, u32)) -> (ScaledFloat, ScaledFloat) {
+    (ScaledFloat::from_scaled(x), ScaledFloat::from_scaled(y))
+}
+
+fn srgb_intent(value: u8) -> Option<SrgbRenderingIntent> {
+    match value {
+        0 => Some(SrgbRenderingIntent::Perceptual),
+        1 => Some(SrgbRenderingIntent::RelativeColorimetric),
+        2 => Some(SrgbRenderingIntent::Saturation),
+        3 => Some(SrgbRenderingIntent::AbsoluteColorimetric),
+        _ => None,
+    }
+}
+
+/// Convert a decoded frame to an image.
+fn frame_image(buffer: &[u8], info: &OutputInfo) -> Result<PlaintextImage, Box<dyn Error>> {
+    let bit_depth = info.bit_depth.try_into()?;
+    let buffer = &buffer[..info.buffer_size()];
+
+    Ok(Image::new(
+        match bit_depth {
+            BitDepth::Eight => buffer.iter().map(|&x| x as u16).collect(),
+            // png stores 16-bit samples in big-endian order
+            BitDepth::Sixteen => buffer
+                .chunks_exact(2)
+                .map(|x| u16::from_be_bytes([x[0], x[1]]))
+                .collect(),
+        },
+        dimension(info.width)?,
+        dimension(info.height)?,
+        info.color_type.try_into()?,
+        bit_depth,
+    ))
+}
+
+fn sample_bytes(image: &PlaintextImage) -> Vec<u8> {
+    match image.bit_depth {
+        BitDepth::Eight => image.eight_bit_data(),
+        BitDepth::Sixteen => image.data.iter().flat_map(|x| x.to_be_bytes()).collect(),
+    }
+}
+
+/// Render a frame onto the canvas at the position given by its frame control.
+fn blend(canvas: &mut PlaintextImage, frame: &PlaintextImage, control: &FrameControl) {
+    let channels = canvas.channel_count() as usize;
+    let has_alpha = matches!(
+        canvas.color_type,
+        ColorType::GrayscaleAlpha | ColorType::Rgba
+    );
+    let max_value = canvas.bit_depth.max_value() as f32;
+
+    for y in 0..frame.size.height as usize {
+        for x in 0..frame.size.width as usize {
+            let source_index = (x + y * frame.size.width as usize) * channels;
+            let target_index = (x
+                + control.x_offset as usize
+                + (y + control.y_offset as usize) * canvas.size.width as usize)
+                * channels;
+            let source = &frame.data[source_index..source_index + channels];
+            let target = &mut canvas.data[target_index..target_index + channels];
+
+            if control.blend_op == BlendOp::Source || !has_alpha {
+                target.copy_from_slice(source);
+                continue;
+            }
+
+            // alpha compositing of the frame over the canvas
+            let source_alpha = source[channels - 1] as f32 / max_value;
+            let target_alpha = target[channels - 1] as f32 / max_value * (1.0 - source_alpha);
+            let alpha = source_alpha + target_alpha;
+            for i in 0..channels - 1 {
+                target[i] = if alpha > 0.0 {
+                    ((source[i] as f32 * source_alpha + target[i] as f32 * target_alpha) / alpha)
+                        .round() as u16
+                } else {
+                    0
+                };
+            }
+            target[channels - 1] = (alpha * max_value).round() as u16;
+        }
+    }
+}
+
+/// Clear the region of a frame to fully transparent black.
+fn clear(canvas: &mut PlaintextImage, control: &FrameControl) {
+    let channels = canvas.channel_count() as usize;
+
+    for y in control.y_offset..control.y_offset + control.height {
+        let start = (control.x_offset + y * canvas.size.width as u32) as usize * channels;
+        canvas.data[start..start + control.width as usize * channels].fill(0);
+    }
+}
+
+impl From<ColorType> for png::ColorType {
+    fn from(value: ColorType) -> Self {
+        match value {
+            ColorType::Grayscale => png::ColorType::Grayscale,
+            ColorType::GrayscaleAlpha => png::ColorType::GrayscaleAlpha,
+            ColorType::Rgb => png::ColorType::Rgb,
+            ColorType::Rgba => png::ColorType::Rgba,
+        }
+    }
+}
+
+impl TryFrom<png::ColorType> for ColorType {
+    type Error = String;
+
+    /// Indexed images have no equivalent and must be expanded to [`ColorType::Rgb`] or
+    /// [`ColorType::Rgba`] while decoding.
+    fn try_from(value: png::ColorType) -> Result<Self, Self::Error> {
+        match value {
+            png::ColorType::Grayscale => Ok(ColorType::Grayscale),
+            png::ColorType::GrayscaleAlpha => Ok(ColorType::GrayscaleAlpha),
+            png::ColorType::Rgb => Ok(ColorType::Rgb),
+            png::ColorType::Rgba => Ok(ColorType::Rgba),
+            png::ColorType::Indexed => Err("Indexed images must be expanded".to_string()),
+        }
+    }
+}
+
+impl From<BitDepth> for png::BitDepth {
+    fn from(value: BitDepth) -> Self {
+        match value {
+            BitDepth::Eight => png::BitDepth::Eight,
+            BitDepth::Sixteen => png::BitDepth::Sixteen,
+        }
+    }
+}
+
+impl TryFrom<png::BitDepth> for BitDepth {
+    type Error = String;
+
+    /// Depths below eight bits have no equivalent and must be expanded to [`BitDepth::Eight`]
+    /// while decoding.
+    fn try_from(value: png::BitDepth) -> Result<Self, Self::Error> {
+        match value {
+            png::BitDepth::Eight => Ok(BitDepth::Eight),
+            png::BitDepth::Sixteen => Ok(BitDepth::Sixteen),
+            depth => Err(format!("{:?} images must be expanded", depth)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_display_metadata_by_default() {
+        let mut icc_profile = vec![0; 128];
+        icc_profile[16..20].copy_from_slice(b"RGB ");
+        let metadata = Metadata {
+            gamma: Some(50000),
+            chromaticities: Some([
+                (31270, 32900),
+                (64000, 33000),
+                (30000, 60000),
+                (15000, 6000),
+            ]),
+            srgb_intent: None,
+            icc_profile: Some(icc_profile),
+            pixel_dimensions: Some(PixelDimensions {
+                x: 2835,
+                y: 2835,
+                per_metre: true,
+            }),
+            text: vec![Text {
+                keyword: "Comment".to_string(),
+                text: "private".to_string(),
+                kind: TextKind::Latin1,
+            }],
+        };
+        let image = Image::new(vec![1, 2, 3], 1, 1, ColorType::Rgb, BitDepth::Eight)
+            .with_metadata(AttachedMetadata::Plaintext(metadata.display_only()));
+
+        let decoded = PngCodec.decode(&PngCodec.encode(&image).unwrap()).unwrap();
+        assert_eq!(decoded, image);
+        let AttachedMetadata::Plaintext(decoded) = decoded.metadata else {
+            panic!("Metadata was not decoded");
+        };
+        assert_eq!(decoded.gamma, metadata.gamma);
+        assert_eq!(decoded.icc_profile, metadata.icc_profile);
+        assert!(decoded.text.is_empty());
+    }
+}
@@ -0,0 +1,290 @@
+use std::error::Error;
+
+use crate::image::codec::{dimension, ImageCodec};
+use crate::image::{BitDepth, ColorType, Image, PlaintextImage};
+
+/// Codec for the netpbm family: pbm (`P1`, `P4`), pgm (`P2`, `P5`), ppm (`P3`, `P6`) and pam
+/// (`P7`).
+///
+/// Images are written as binary pgm or ppm, or as pam if they have an alpha channel.
+pub struct PnmCodec;
+
+impl ImageCodec for PnmCodec {
+    fn decode(&self, data: &[u8]) -> Result<PlaintextImage, Box<dyn Error>> {
+        let mut header = Header { data, position: 0 };
+        let magic = header.next_token().ok_or("Missing pnm magic number")?;
+
+        let (width, height, channels, max_value) = match magic {
+            b"P1" | b"P4" => (header.next_number()?, header.next_number()?, 1, 1),
+            b"P2" | b"P3" | b"P5" | b"P6" => (
+                header.next_number()?,
+                header.next_number()?,
+                if magic == b"P3" || magic == b"P6" {
+                    3
+                } else {
+                    1
+                },
+                header.next_number()?,
+            ),
+            b"P7" => header.pam_fields()?,
+            _ => return Err("Unknown pnm magic number".into()),
+        };
+        if max_value == 0 || max_value > u16::MAX as u32 {
+            return Err(format!("Invalid pnm maximum value {}", max_value).into());
+        }
+        let color_type = match channels {
+            1 => ColorType::Grayscale,
+            2 => ColorType::GrayscaleAlpha,
+            3 => ColorType::Rgb,
+            4 => ColorType::Rgba,
+            _ => return Err(format!("Unsupported pam depth {}", channels).into()),
+        };
+        let (width, height) = (dimension(width)?, dimension(height)?);
+        // rows of binary pbm images are padded to whole bytes, which an empty row has none of
+        if magic == b"P4" && width == 0 {
+            return Err("Invalid pbm width 0".into());
+        }
+        // exactly one whitespace character separates the header from binary data
+        let raster = data.get(header.position + 1..).unwrap_or_default();
+        let sample_count = width as usize * height as usize * channels;
+
+        let samples = match magic {
+            // in pbm images, 1 is black
+            b"P1" => std::iter::from_fn(|| header.next_bit())
+                .take(sample_count)
+                .map(|bit| bit.map(|bit| 1 - bit))
+                .collect::<Result<Vec<u32>, _>>()?,
+            b"P2" | b"P3" => std::iter::from_fn(|| Some(header.next_number()))
+                .take(sample_count)
+                .collect::<Result<Vec<u32>, _>>()?,
+            b"P4" => raster
+                .chunks_exact((width as usize).div_ceil(8))
+                .flat_map(|row| {
+                    (0..width as usize).map(move |x| 1 - u32::from((row[x / 8] >> (7 - x % 8)) & 1))
+                })
+                .take(sample_count)
+                .collect(),
+            _ if max_value > u8::MAX as u32 => raster
+                .chunks_exact(2)
+                .map(|x| u16::from_be_bytes([x[0], x[1]]) as u32)
+                .take(sample_count)
+                .collect(),
+            _ => raster
+                .iter()
+                .map(|&x| x as u32)
+                .take(sample_count)
+                .collect(),
+        };
+        if samples.len() != sample_count {
+            return Err("Truncated pnm image data".into());
+        }
+
+        let bit_depth = if max_value > u8::MAX as u32 {
+            BitDepth::Sixteen
+        } else {
+            BitDepth::Eight
+        };
+        let target_max_value = bit_depth.max_value() as u32;
+
+        Ok(Image::new(
+            samples
+                .into_iter()
+                .map(|x| ((x.min(max_value) * target_max_value + max_value / 2) / max_value) as u16)
+                .collect(),
+            width,
+            height,
+            color_type,
+            bit_depth,
+        ))
+    }
+
+    fn encode(&self, image: &PlaintextImage) -> Result<Vec<u8>, Box<dyn Error>> {
+        let (width, height, max_value) = (
+            image.size.width,
+            image.size.height,
+            image.bit_depth.max_value(),
+        );
+        let mut buffer = match image.color_type {
+            ColorType::Grayscale => format!("P5\n{} {}\n{}\n", width, height, max_value),
+            ColorType::Rgb => format!("P6\n{} {}\n{}\n", width, height, max_value),
+            ColorType::GrayscaleAlpha | ColorType::Rgba => format!(
+                "P7\nWIDTH {}\nHEIGHT {}\nDEPTH {}\nMAXVAL {}\nTUPLTYPE {}\nENDHDR\n",
+                width,
+                height,
+                image.channel_count(),
+                max_value,
+                if image.color_type == ColorType::Rgba {
+                    "RGB_ALPHA"
+                } else {
+                    "GRAYSCALE_ALPHA"
+                }
+            ),
+        }
+        .into_bytes();
+
+        match image.bit_depth {
+            BitDepth::Eight => buffer.extend(image.eight_bit_data()),
+            BitDepth::Sixteen => buffer.extend(image.data.iter().flat_map(|x| x.to_be_bytes())),
+        }
+
+        Ok(buffer)
+    }
+}
+
+/// Reads whitespace separated tokens from a pnm header, skipping comments.
+struct Header<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Header<'a> {
+    fn next_token(&mut self) -> Option<&'a [u8]> {
+        loop {
+            match self.data.get(self.position)? {
+                b'#' => {
+                    while !matches!(self.data.get(self.position), Some(b'\n') | None) {
+                        self.position += 1;
+                    }
+                }
+                x if x.is_ascii_whitespace() => self.position += 1,
+                _ => break,
+            }
+        }
+
+        let start = self.position;
+        while matches!(self.data.get(self.position), Some(x) if !x.is_ascii_whitespace()) {
+            self.position += 1;
+        }
+
+        Some(&self.data[start..self.position])
+    }
+
+    fn next_number(&mut self) -> Result<u32, Box<dyn Error>> {
+        let token = self.next_token().ok_or("Truncated pnm header")?;
+
+        Ok(std::str::from_utf8(token)?.parse()?)
+    }
+
+    /// Plain pbm images may omit whitespace between bits, so they are read one digit at a time.
+    fn next_bit(&mut self) -> Option<Result<u32, Box<dyn Error>>> {
+        while let Some(x) = self.data.get(self.position) {
+            self.position += 1;
+            match x {
+                b'0' | b'1' => return Some(Ok(u32::from(x - b'0'))),
+                x if x.is_ascii_whitespace() => {}
+                _ => return Some(Err("Invalid pbm bit".into())),
+            }
+        }
+
+        None
+    }
+
+    /// Read the header fields of a pam image, returning width, height, depth and maximum value.
+    fn pam_fields(&mut self) -> Result<(u32, u32, usize, u32), Box<dyn Error>> {
+        let (mut width, mut height, mut depth, mut max_value) = (None, None, None, None);
+
+        loop {
+            match self.next_token().ok_or("Truncated pam header")? {
+                b"WIDTH" => width = Some(self.next_number()?),
+                b"HEIGHT" => height = Some(self.next_number()?),
+                b"DEPTH" => depth = Some(self.next_number()? as usize),
+                b"MAXVAL" => max_value = Some(self.next_number()?),
+                b"TUPLTYPE" => {
+                    self.next_token();
+                }
+                b"ENDHDR" => break,
+                _ => return Err("Unknown pam header field".into()),
+            }
+        }
+
+        Ok((
+            width.ok_or("Missing pam width")?,
+            height.ok_or("Missing pam height")?,
+            depth.ok_or("Missing pam depth")?,
+            max_value.ok_or("Missing pam maximum value")?,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(image: PlaintextImage) {
+        let encoded = PnmCodec.encode(&image).unwrap();
+        assert_eq!(PnmCodec.decode(&encoded).unwrap(), image);
+    }
+
+    #[test]
+    fn round_trips_every_colour_type() {
+        round_trip(Image::new(
+            vec![0, 100, 200, 255],
+            2,
+            2,
+            ColorType::Grayscale,
+            BitDepth::Eight,
+        ));
+        round_trip(Image::new(
+            vec![0, 255, 10, 128, 20, 0],
+            3,
+            1,
+            ColorType::GrayscaleAlpha,
+            BitDepth::Eight,
+        ));
+        round_trip(Image::new(
+            (0..18).map(|x| x * 14).collect(),
+            3,
+            2,
+            ColorType::Rgb,
+            BitDepth::Eight,
+        ));
+        round_trip(Image::new(
+            vec![1, 2, 3, 4, 250, 251, 252, 253],
+            1,
+            2,
+            ColorType::Rgba,
+            BitDepth::Eight,
+        ));
+    }
+
+    #[test]
+    fn round_trips_sixteen_bit_samples() {
+        round_trip(Image::new(
+            vec![0, 256, 4096, 65535],
+            4,
+            1,
+            ColorType::Grayscale,
+            BitDepth::Sixteen,
+        ));
+        round_trip(Image::new(
+            vec![65535, 1, 300, 0, 40000, 12345],
+            2,
+            1,
+            ColorType::Rgb,
+            BitDepth::Sixteen,
+        ));
+    }
+
+    #[test]
+    fn decodes_plain_formats() {
+        let bitmap = PnmCodec.decode(b"P1\n# a comment\n3 1\n101").unwrap();
+        assert_eq!(bitmap.data, [0, 255, 0]);
+
+        let graymap = PnmCodec.decode(b"P2 2 1 15 0 15").unwrap();
+        assert_eq!(graymap.data, [0, 255]);
+        assert_eq!(graymap.color_type, ColorType::Grayscale);
+    }
+
+    #[test]
+    fn rejects_truncated_data() {
+        assert!(PnmCodec.decode(b"P5\n2 2\n255\n\x00\x01\x02").is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_headers() {
+        assert!(PnmCodec.decode(b"P4\n0 1\n\x00").is_err());
+        assert!(PnmCodec
+            .decode(b"P7\nWIDTH 65535\nHEIGHT 65535\nDEPTH 4294967295\nMAXVAL 255\nENDHDR\n")
+            .is_err());
+    }
+}
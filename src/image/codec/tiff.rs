@@ -0,0 +1,182 @@
+use std::error::Error;
+use std::io::Cursor;
+
+use tiff::decoder::{Decoder, DecodingResult};
+use tiff::encoder::{colortype, TiffEncoder};
+
+use crate::image::codec::{dimension, gray_alpha_to_rgba, ImageCodec};
+use crate::image::{BitDepth, ColorType, Image, PlaintextImage};
+
+/// Codec for uncompressed and losslessly compressed tiff images with 8 or 16 bits per sample.
+///
+/// Grayscale images with a white-is-zero photometric interpretation are inverted by the decoder,
+/// so their samples can be read like black-is-zero ones. CMYK images are converted to rgb. Tiff has
+/// no grayscale alpha colour type, so those images are written as rgba.
+pub struct TiffCodec;
+
+impl ImageCodec for TiffCodec {
+    fn decode(&self, data: &[u8]) -> Result<PlaintextImage, Box<dyn Error>> {
+        let mut decoder = Decoder::new(Cursor::new(data))?;
+        let (width, height) = decoder.dimensions()?;
+        let color_type = decoder.colortype()?;
+
+        let bits = match color_type {
+            tiff::ColorType::Gray(bits)
+            | tiff::ColorType::GrayA(bits)
+            | tiff::ColorType::RGB(bits)
+            | tiff::ColorType::RGBA(bits)
+            | tiff::ColorType::CMYK(bits) => bits,
+            color_type => {
+                return Err(format!("Unsupported tiff colour type {:?}", color_type).into())
+            }
+        };
+        // other depths are packed, so the decoder cannot return them a sample at a time
+        if bits != 8 && bits != 16 {
+            return Err(format!(
+                "Only 8-bit and 16-bit tiff images are supported, not {}-bit",
+                bits
+            )
+            .into());
+        }
+
+        let (data, bit_depth) = match decoder.read_image()? {
+            DecodingResult::U8(data) => (
+                data.into_iter().map(|x| x as u16).collect::<Vec<u16>>(),
+                BitDepth::Eight,
+            ),
+            DecodingResult::U16(data) => (data, BitDepth::Sixteen),
+            _ => return Err("Only 8-bit and 16-bit tiff images are supported".into()),
+        };
+        let (data, color_type) = match color_type {
+            tiff::ColorType::Gray(_) => (data, ColorType::Grayscale),
+            tiff::ColorType::GrayA(_) => (data, ColorType::GrayscaleAlpha),
+            tiff::ColorType::RGB(_) => (data, ColorType::Rgb),
+            tiff::ColorType::RGBA(_) => (data, ColorType::Rgba),
+            // cmyk is the only other colour type accepted above
+            _ => {
+                let max_value = bit_depth.max_value() as u32;
+                (
+                    data.chunks_exact(4)
+                        .flat_map(|pixel| {
+                            let k = max_value - pixel[3] as u32;
+                            [0, 1, 2]
+                                .map(|i| ((max_value - pixel[i] as u32) * k / max_value) as u16)
+                        })
+                        .collect(),
+                    ColorType::Rgb,
+                )
+            }
+        };
+
+        Ok(Image::new(
+            data,
+            dimension(width)?,
+            dimension(height)?,
+            color_type,
+            bit_depth,
+        ))
+    }
+
+    fn encode(&self, image: &PlaintextImage) -> Result<Vec<u8>, Box<dyn Error>> {
+        let (width, height) = (image.size.width as u32, image.size.height as u32);
+        let mut buffer = Vec::new();
+        let mut encoder = TiffEncoder::new(Cursor::new(&mut buffer))?;
+
+        match (image.bit_depth, image.color_type) {
+            (BitDepth::Eight, ColorType::Grayscale) => {
+                encoder.write_image::<colortype::Gray8>(width, height, &image.eight_bit_data())?
+            }
+            (BitDepth::Eight, ColorType::GrayscaleAlpha) => encoder
+                .write_image::<colortype::RGBA8>(
+                    width,
+                    height,
+                    &gray_alpha_to_rgba(&image.eight_bit_data()),
+                )?,
+            (BitDepth::Eight, ColorType::Rgb) => {
+                encoder.write_image::<colortype::RGB8>(width, height, &image.eight_bit_data())?
+            }
+            (BitDepth::Eight, ColorType::Rgba) => {
+                encoder.write_image::<colortype::RGBA8>(width, height, &image.eight_bit_data())?
+            }
+            (BitDepth::Sixteen, ColorType::Grayscale) => {
+                encoder.write_image::<colortype::Gray16>(width, height, &image.data)?
+            }
+            (BitDepth::Sixteen, ColorType::GrayscaleAlpha) => encoder
+                .write_image::<colortype::RGBA16>(
+                    width,
+                    height,
+                    &gray_alpha_to_rgba(&image.data),
+                )?,
+            (BitDepth::Sixteen, ColorType::Rgb) => {
+                encoder.write_image::<colortype::RGB16>(width, height, &image.data)?
+            }
+            (BitDepth::Sixteen, ColorType::Rgba) => {
+                encoder.write_image::<colortype::RGBA16>(width, height, &image.data)?
+            }
+        }
+
+        Ok(buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tiff::encoder::colortype::ColorType as TiffColorType;
+    use tiff::tags::{PhotometricInterpretation, SampleFormat};
+
+    use super::*;
+
+    struct WhiteIsZero8;
+    impl TiffColorType for WhiteIsZero8 {
+        type Inner = u8;
+        const TIFF_VALUE: PhotometricInterpretation = PhotometricInterpretation::WhiteIsZero;
+        const BITS_PER_SAMPLE: &'static [u16] = &[8];
+        const SAMPLE_FORMAT: &'static [SampleFormat] = &[SampleFormat::Uint];
+    }
+
+    struct Gray4;
+    impl TiffColorType for Gray4 {
+        type Inner = u8;
+        const TIFF_VALUE: PhotometricInterpretation = PhotometricInterpretation::BlackIsZero;
+        const BITS_PER_SAMPLE: &'static [u16] = &[4];
+        const SAMPLE_FORMAT: &'static [SampleFormat] = &[SampleFormat::Uint];
+    }
+
+    fn encode<C: TiffColorType<Inner = u8>>(width: u32, height: u32, data: &[u8]) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        TiffEncoder::new(Cursor::new(&mut buffer))
+            .unwrap()
+            .write_image::<C>(width, height, data)
+            .unwrap();
+
+        buffer
+    }
+
+    #[test]
+    fn round_trips_sixteen_bit_rgb() {
+        let image = Image::new(
+            vec![0, 1, 65535, 300, 40000, 12345],
+            2,
+            1,
+            ColorType::Rgb,
+            BitDepth::Sixteen,
+        );
+        let encoded = TiffCodec.encode(&image).unwrap();
+
+        assert_eq!(TiffCodec.decode(&encoded).unwrap(), image);
+    }
+
+    #[test]
+    fn inverts_white_is_zero() {
+        let decoded = TiffCodec
+            .decode(&encode::<WhiteIsZero8>(2, 1, &[0, 200]))
+            .unwrap();
+
+        assert_eq!(decoded.data, [255, 55]);
+    }
+
+    #[test]
+    fn rejects_packed_samples() {
+        assert!(TiffCodec.decode(&encode::<Gray4>(2, 1, &[0, 15])).is_err());
+    }
+}
@@ -0,0 +1,241 @@
+use std::fmt::{Debug, Formatter};
+
+use clap::ValueEnum;
+use log::trace;
+use serde::{Deserialize, Serialize};
+
+use crate::crypt::operations::{absolute_difference, select, square, sum};
+use crate::crypt::{EncryptedImageData, ServerKeyType, Width};
+use crate::image::{EncryptedImage, Image, Size};
+use crate::progress;
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy, ValueEnum)]
+pub enum Metric {
+    /// The sum of the absolute differences of all samples.
+    #[value(alias = "sad")]
+    SumOfAbsoluteDifferences,
+    /// The mean of the squared differences of all samples.
+    #[value(alias = "mse")]
+    MeanSquaredError,
+}
+
+/// An encrypted sum over all samples for a metric, along with the number of samples.
+///
+/// Dividing by a number of samples is not possible on ciphertexts, so means are computed by the
+/// client after decrypting the sum.
+#[derive(PartialEq, Eq, Serialize, Deserialize, Clone)]
+pub struct Measurement {
+    pub metric: Metric,
+    pub sum: EncryptedImageData,
+    pub count: u64,
+}
+
+impl Debug for Measurement {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Encrypted measurement ({:?} over {} samples)",
+            self.metric, self.count
+        )
+    }
+}
+
+/// How well a template matches every position of an image.
+#[derive(PartialEq, Eq, Serialize, Deserialize, Clone)]
+pub struct ScoreMap {
+    /// The number of positions the template fits at horizontally and vertically.
+    pub size: Size,
+    /// The sum of absolute differences at every position row by row, lower being more similar.
+    pub scores: Vec<EncryptedImageData>,
+    /// The x and y coordinates of the position with the lowest score.
+    pub best_match: Option<(EncryptedImageData, EncryptedImageData)>,
+}
+
+impl Debug for ScoreMap {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Encrypted score map ({:?}, best match {})",
+            self.size,
+            if self.best_match.is_some() {
+                "located"
+            } else {
+                "not located"
+            }
+        )
+    }
+}
+
+/// The absolute difference of every sample of two images.
+///
+/// Returns `None` if the images differ in size, colour type or bit depth.
+pub fn difference(
+    image: &EncryptedImage,
+    reference: &EncryptedImage,
+    key: &ServerKeyType,
+) -> Option<EncryptedImage> {
+    if !is_comparable(image, reference) {
+        return None;
+    }
+
+    Some(
+        Image::new(
+            differences(image, reference, key).collect(),
+            image.size.width,
+            image.size.height,
+            image.color_type,
+            image.bit_depth,
+        )
+        .with_metadata(image.metadata.clone()),
+    )
+}
+
+/// Measure how different two images are.
+///
+/// Returns `None` if the images differ in size, colour type or bit depth.
+pub fn measure(
+    image: &EncryptedImage,
+    reference: &EncryptedImage,
+    metric: Metric,
+    key: &ServerKeyType,
+) -> Option<Measurement> {
+    if !is_comparable(image, reference) {
+        return None;
+    }
+
+    let differences = differences(image, reference, key);
+    let (values, bits) = match metric {
+        Metric::SumOfAbsoluteDifferences => {
+            (differences.collect::<Vec<_>>(), image.bit_depth.bits())
+        }
+        Metric::MeanSquaredError => (
+            differences.map(|x| square(&x, key)).collect(),
+            2 * image.bit_depth.bits(),
+        ),
+    };
+    let count = values.len() as u64;
+    // the sum needs enough bits on top of a single value to count the samples
+    let width = Width::for_bits(bits + u64::BITS - count.leading_zeros());
+
+    Some(Measurement {
+        metric,
+        sum: sum(&values, width, key),
+        count,
+    })
+}
+
+/// Slide a template over an image and score every position by the sum of absolute differences,
+/// optionally also locating the best match.
+///
+/// Returns `None` if the template is empty, larger than the image, or differs from it in colour
+/// type or bit depth.
+pub fn match_template(
+    image: &EncryptedImage,
+    template: &EncryptedImage,
+    locate: bool,
+    key: &ServerKeyType,
+) -> Option<ScoreMap> {
+    if template.data.is_empty()
+        || template.size.width > image.size.width
+        || template.size.height > image.size.height
+        || template.color_type != image.color_type
+        || template.bit_depth != image.bit_depth
+    {
+        return None;
+    }
+
+    let size = Size {
+        width: image.size.width - template.size.width + 1,
+        height: image.size.height - template.size.height + 1,
+    };
+    let count = template.data.len() as u64;
+    let width = Width::for_bits(image.bit_depth.bits() + u64::BITS - count.leading_zeros());
+    let mut scores = Vec::with_capacity(size.width as usize * size.height as usize);
+    progress::expect(size.pixel_count());
+
+    for y in 0..size.height {
+        for x in 0..size.width {
+            trace!("Position: ({}, {})", x, y);
+            progress::step();
+
+            let mut differences = Vec::with_capacity(count as usize);
+            for template_y in 0..template.size.height {
+                for template_x in 0..template.size.width {
+                    let pixel = image.get_pixel(x + template_x, y + template_y).unwrap();
+                    let template_pixel = template.get_pixel(template_x, template_y).unwrap();
+
+                    differences.extend(
+                        pixel
+                            .into_iter()
+                            .zip(template_pixel)
+                            .map(|(a, b)| absolute_difference(a, b, key)),
+                    );
+                }
+            }
+
+            scores.push(sum(&differences, width, key));
+        }
+    }
+
+    let best_match = locate.then(|| best_match(&scores, size, key));
+
+    Some(ScoreMap {
+        size,
+        scores,
+        best_match,
+    })
+}
+
+/// Find the coordinates of the lowest score, keeping the first one if several are equal.
+fn best_match(
+    scores: &[EncryptedImageData],
+    size: Size,
+    key: &ServerKeyType,
+) -> (EncryptedImageData, EncryptedImageData) {
+    let coordinate =
+        |value: u16| key.create_trivial_radix(value as u64, Width::SIXTEEN.num_blocks());
+    let (mut best_score, mut best_x, mut best_y) =
+        (scores[0].clone(), coordinate(0), coordinate(0));
+
+    for (index, score) in scores.iter().enumerate().skip(1) {
+        let (x, y) = (
+            (index % size.width as usize) as u16,
+            (index / size.width as usize) as u16,
+        );
+        let is_better = key.lt_parallelized(score, &best_score);
+
+        best_score = select(&is_better, score, &best_score, key);
+        best_x = select(&is_better, &coordinate(x), &best_x, key);
+        best_y = select(&is_better, &coordinate(y), &best_y, key);
+    }
+
+    (best_x, best_y)
+}
+
+/// The absolute differences of all samples of two images of the same layout.
+fn differences<'a>(
+    image: &'a EncryptedImage,
+    reference: &'a EncryptedImage,
+    key: &'a ServerKeyType,
+) -> impl Iterator<Item = EncryptedImageData> + 'a {
+    let channels = image.channel_count() as usize;
+    progress::expect(image.size.pixel_count());
+
+    image
+        .data
+        .chunks(channels)
+        .zip(reference.data.chunks(channels))
+        .flat_map(move |(pixel, reference_pixel)| {
+            progress::step();
+            pixel
+                .iter()
+                .zip(reference_pixel)
+                .map(move |(x, y)| absolute_difference(x, y, key))
+        })
+}
+
+fn is_comparable(image: &EncryptedImage, reference: &EncryptedImage) -> bool {
+    image.size == reference.size
+        && image.color_type == reference.color_type
+        && image.bit_depth == reference.bit_depth
+}
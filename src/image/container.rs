@@ -0,0 +1,244 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read};
+use std::path::Path;
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::crypt::digest::Digest;
+use crate::crypt::{ParameterSet, Width, PARAMETER_SET};
+use crate::image::{BitDepth, ColorType, EncryptedImage, Image, Size};
+
+/// The magic bytes at the start of every encrypted image file.
+pub const MAGIC_BYTES: [u8; 4] = *b"FHEI";
+/// The current version of the encrypted image file format.
+pub const VERSION: u16 = 2;
+
+/// The header of an encrypted image file, followed by the ciphertexts of all samples and the
+/// attached metadata.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Header {
+    magic_bytes: [u8; 4],
+    version: u16,
+    size: Size,
+    color_type: ColorType,
+    bit_depth: BitDepth,
+    parameter_set: ParameterSet,
+    key_digest: Digest,
+}
+
+impl EncryptedImage {
+    /// Write this image to an encrypted image file.
+    ///
+    /// The digest of the server key belonging to the key the image was encrypted with is stored so
+    /// it can be checked when loading.
+    pub fn save_encrypted(
+        &self,
+        file_path: &Path,
+        key_digest: Digest,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut writer = BufWriter::new(File::create(file_path)?);
+        let header = Header {
+            magic_bytes: MAGIC_BYTES,
+            version: VERSION,
+            size: self.size,
+            color_type: self.color_type,
+            bit_depth: self.bit_depth,
+            parameter_set: PARAMETER_SET,
+            key_digest,
+        };
+
+        bincode::serialize_into(&mut writer, &header)?;
+        bincode::serialize_into(&mut writer, &self.data)?;
+        bincode::serialize_into(&mut writer, &self.metadata)?;
+        debug!("Wrote {:?} to {:?}", self, file_path);
+
+        Ok(())
+    }
+
+    /// Read an image from an encrypted image file.
+    ///
+    /// Fails if the file was written by an incompatible version, uses different parameters or was
+    /// encrypted with a key other than the one whose server key has the given digest, or if its
+    /// samples do not match its header.
+    pub fn load_encrypted(
+        file_path: &Path,
+        key_digest: Digest,
+    ) -> Result<EncryptedImage, Box<dyn Error>> {
+        let mut reader = BufReader::new(File::open(file_path)?);
+        let header: Header = bincode::deserialize_from(&mut reader)?;
+
+        if header.magic_bytes != MAGIC_BYTES {
+            return Err("Not an encrypted image file".into());
+        }
+        if header.version != VERSION {
+            return Err(format!("Unsupported encrypted image version {}", header.version).into());
+        }
+        if header.parameter_set != PARAMETER_SET {
+            return Err(format!("Unsupported parameter set {:?}", header.parameter_set).into());
+        }
+        if header.key_digest != key_digest {
+            return Err("Image was encrypted with a different key".into());
+        }
+
+        let data: Vec<_> = bincode::deserialize_from(&mut reader)?;
+        let sample_count =
+            header.size.pixel_count() as usize * u16::from(header.color_type) as usize;
+        if data.len() != sample_count {
+            return Err(format!(
+                "Encrypted image has {} samples instead of {}",
+                data.len(),
+                sample_count
+            )
+            .into());
+        }
+        let width = Width::for_bits(header.bit_depth.bits());
+        if data.iter().any(|x| Width::of(x) != width) {
+            return Err(format!(
+                "Encrypted image samples are not {}-bit ciphertexts",
+                width.bits()
+            )
+            .into());
+        }
+
+        let image = Image::new(
+            data,
+            header.size.width,
+            header.size.height,
+            header.color_type,
+            header.bit_depth,
+        )
+        .with_metadata(bincode::deserialize_from(&mut reader)?);
+        debug!("Loaded {:?} from {:?}", image, file_path);
+
+        Ok(image)
+    }
+
+    /// Check whether a file starts with the magic bytes of an encrypted image file.
+    pub fn is_encrypted_file(file_path: &Path) -> Result<bool, Box<dyn Error>> {
+        let mut magic_bytes = [0; MAGIC_BYTES.len()];
+
+        match File::open(file_path)?.read_exact(&mut magic_bytes) {
+            Ok(()) => Ok(magic_bytes == MAGIC_BYTES),
+            Err(error) if error.kind() == ErrorKind::UnexpectedEof => Ok(false),
+            Err(error) => Err(error.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::marker::PhantomData;
+    use std::path::PathBuf;
+
+    use tfhe::core_crypto::prelude::{CiphertextModulus, LweCiphertext};
+    use tfhe::shortint::ciphertext::Degree;
+    use tfhe::shortint::{CarryModulus, CiphertextBig, MessageModulus};
+
+    use super::*;
+    use crate::crypt::{EncryptedImageData, BLOCK_BITS};
+    use crate::image::metadata::{AttachedMetadata, Metadata};
+
+    const DIGEST: Digest = [7; 32];
+
+    /// A ciphertext of the given width that is never decrypted, so it needs no key.
+    fn sample(width: Width) -> EncryptedImageData {
+        let block = CiphertextBig {
+            ct: LweCiphertext::from_container(vec![0; 2], CiphertextModulus::new_native()),
+            degree: Degree(0),
+            message_modulus: MessageModulus(1 << BLOCK_BITS),
+            carry_modulus: CarryModulus(1 << BLOCK_BITS),
+            _order_marker: PhantomData,
+        };
+
+        vec![block; width.num_blocks()].into()
+    }
+
+    /// A path in the temporary directory, which is removed when the test is done.
+    struct TemporaryFile(PathBuf);
+
+    impl TemporaryFile {
+        fn new(name: &str) -> Self {
+            Self(env::temp_dir().join(format!("fheip-{}-{}.fhe", std::process::id(), name)))
+        }
+    }
+
+    impl Drop for TemporaryFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn gray(data: Vec<EncryptedImageData>, width: u16) -> EncryptedImage {
+        Image::new(data, width, 1, ColorType::Grayscale, BitDepth::Eight)
+    }
+
+    #[test]
+    fn round_trips_samples_and_metadata() {
+        let file = TemporaryFile::new("round-trip");
+        let image = gray(vec![sample(Width::EIGHT); 2], 2).with_metadata(
+            AttachedMetadata::Plaintext(Metadata {
+                gamma: Some(45455),
+                ..Metadata::default()
+            }),
+        );
+
+        image.save_encrypted(&file.0, DIGEST).unwrap();
+        assert!(EncryptedImage::is_encrypted_file(&file.0).unwrap());
+        let loaded = EncryptedImage::load_encrypted(&file.0, DIGEST).unwrap();
+        assert_eq!(loaded.size, image.size);
+        assert_eq!(loaded.data, image.data);
+        assert_eq!(loaded.metadata, image.metadata);
+    }
+
+    #[test]
+    fn rejects_other_keys() {
+        let file = TemporaryFile::new("other-key");
+        gray(Vec::new(), 0).save_encrypted(&file.0, DIGEST).unwrap();
+
+        let error = EncryptedImage::load_encrypted(&file.0, [8; 32]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Image was encrypted with a different key"
+        );
+    }
+
+    #[test]
+    fn rejects_missing_samples() {
+        let file = TemporaryFile::new("missing-samples");
+        // a header announcing two samples without any following
+        gray(Vec::new(), 2).save_encrypted(&file.0, DIGEST).unwrap();
+
+        let error = EncryptedImage::load_encrypted(&file.0, DIGEST).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Encrypted image has 0 samples instead of 2"
+        );
+    }
+
+    #[test]
+    fn rejects_samples_of_another_width() {
+        let file = TemporaryFile::new("other-width");
+        gray(vec![sample(Width::SIXTEEN)], 1)
+            .save_encrypted(&file.0, DIGEST)
+            .unwrap();
+
+        let error = EncryptedImage::load_encrypted(&file.0, DIGEST).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Encrypted image samples are not 8-bit ciphertexts"
+        );
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let file = TemporaryFile::new("other-file");
+        fs::write(&file.0, b"P5\n1 1\n255\n\x00").unwrap();
+
+        assert!(!EncryptedImage::is_encrypted_file(&file.0).unwrap());
+        assert!(EncryptedImage::load_encrypted(&file.0, DIGEST).is_err());
+    }
+}
@@ -0,0 +1,182 @@
+use clap::ValueEnum;
+use log::trace;
+use serde::{Deserialize, Serialize};
+
+use crate::crypt::operations::{narrow, step_function, widen};
+use crate::crypt::{EncryptedImageData, ServerKeyType, Width};
+use crate::image::{EncryptedImage, EncryptedImageView, Image};
+use crate::progress;
+
+/// A 4x4 Bayer matrix, giving the order in which positions of a tile reach the next level.
+const BAYER_MATRIX: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+/// The Floyd–Steinberg weights in sixteenths for the neighbours a pixel's error is passed on to.
+const ERROR_DIFFUSION: [(i32, i32, u64); 4] = [(1, 0, 7), (-1, 1, 3), (0, 1, 5), (1, 1, 1)];
+
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize, Clone, Copy, ValueEnum)]
+pub enum Dither {
+    /// Round every value to the nearest level.
+    #[default]
+    None,
+    /// Vary the thresholds between levels with a Bayer matrix.
+    Ordered,
+    /// Pass the rounding error of every value on to its neighbours (Floyd–Steinberg).
+    ErrorDiffusion,
+}
+
+/// Reduce every colour channel to the given number of evenly spaced levels, keeping alpha.
+///
+/// Returns `None` if there are fewer than two levels or more than values of the image's bit depth.
+pub fn posterise(
+    image: &EncryptedImageView,
+    levels: u16,
+    dither: Dither,
+    key: &ServerKeyType,
+) -> Option<EncryptedImage> {
+    let max_value = image.bit_depth.max_value();
+    if levels < 2 || levels as u32 > max_value as u32 + 1 {
+        return None;
+    }
+
+    let quantiser = Quantiser::new(max_value, levels);
+    progress::expect(image.size.pixel_count());
+    let data = match dither {
+        Dither::None | Dither::Ordered => {
+            let mut data = Vec::with_capacity(image.data.len());
+
+            for y in 0..image.size.height {
+                for x in 0..image.size.width {
+                    trace!("Pixel: ({}, {})", x, y);
+                    progress::step();
+
+                    let offset = match dither {
+                        Dither::Ordered => {
+                            (BAYER_MATRIX[y as usize % 4][x as usize % 4] as f64 + 0.5) / 16.0
+                        }
+                        _ => 0.5,
+                    };
+                    let steps = quantiser.steps(offset, 0);
+
+                    for (channel, value) in image.get_pixel(x, y).unwrap().into_iter().enumerate() {
+                        data.push(if image.color_type.is_alpha(channel) {
+                            (*value).clone()
+                        } else {
+                            step_function(value, &steps, key)
+                        });
+                    }
+                }
+            }
+
+            data
+        }
+        Dither::ErrorDiffusion => error_diffusion(image, &quantiser, key),
+    };
+
+    Some(
+        Image::new(
+            data,
+            image.size.width,
+            image.size.height,
+            image.color_type,
+            image.bit_depth,
+        )
+        .with_metadata(image.metadata.cloned()),
+    )
+}
+
+/// Apply Floyd–Steinberg dithering row by row, since every pixel depends on the errors of the
+/// pixels before it.
+///
+/// Errors can be negative, so values are kept with a bias added that covers the largest error.
+fn error_diffusion(
+    image: &EncryptedImageView,
+    quantiser: &Quantiser,
+    key: &ServerKeyType,
+) -> Vec<EncryptedImageData> {
+    let width = Width::for_bits(image.bit_depth.bits());
+    // adjusted values stay within one maximum value beyond the range, the bias doubles that and
+    // the weighted errors need three more bits
+    let working_width = width.with_headroom(2 + 3);
+    let bias = 2 * (quantiser.max_value + 1);
+    let steps = quantiser.steps(0.5, bias);
+    let channels = image.channel_count() as usize;
+    let (image_width, image_height) = (image.size.width as i32, image.size.height as i32);
+
+    let mut adjusted = image
+        .data
+        .iter()
+        .map(|x| key.scalar_add_parallelized(&widen(x, working_width, key), bias))
+        .collect::<Vec<_>>();
+    let mut data = image.data.iter().map(|&x| x.clone()).collect::<Vec<_>>();
+
+    for y in 0..image_height {
+        for x in 0..image_width {
+            trace!("Pixel: ({}, {})", x, y);
+            progress::step();
+
+            for channel in 0..channels {
+                let index = (y as usize * image_width as usize + x as usize) * channels + channel;
+                if image.color_type.is_alpha(channel) {
+                    continue;
+                }
+
+                let level = step_function(&adjusted[index], &steps, key);
+                // the error with the bias still added
+                let error = key.sub_parallelized(&adjusted[index], &level);
+
+                for (dx, dy, weight) in ERROR_DIFFUSION {
+                    let (x, y) = (x + dx, y + dy);
+                    if x < 0 || x >= image_width || y >= image_height {
+                        continue;
+                    }
+
+                    let neighbour =
+                        (y as usize * image_width as usize + x as usize) * channels + channel;
+                    let weighted_error = key.unchecked_scalar_right_shift_parallelized(
+                        &key.scalar_mul_parallelized(&error, weight),
+                        4,
+                    );
+                    adjusted[neighbour] = key.scalar_sub_parallelized(
+                        &key.add_parallelized(&adjusted[neighbour], &weighted_error),
+                        bias * weight / 16,
+                    );
+                }
+
+                data[index] = narrow(&level, width, key);
+            }
+        }
+    }
+
+    data
+}
+
+/// The plaintext side of reducing values to evenly spaced levels.
+struct Quantiser {
+    max_value: u64,
+    levels: u64,
+}
+
+impl Quantiser {
+    fn new(max_value: u16, levels: u16) -> Self {
+        Self {
+            max_value: max_value as u64,
+            levels: levels as u64,
+        }
+    }
+
+    /// The steps from each level to the next, with the threshold at the given fraction of the way
+    /// between them and shifted by a bias.
+    fn steps(&self, offset: f64, bias: u64) -> Vec<(u64, u64)> {
+        let step = self.max_value as f64 / (self.levels - 1) as f64;
+
+        (1..self.levels)
+            .map(|level| {
+                let threshold = ((level as f64 - 1.0 + offset) * step).ceil() as u64;
+                let increase = (level as f64 * step).round() as u64
+                    - ((level - 1) as f64 * step).round() as u64;
+
+                (threshold + bias, increase)
+            })
+            .collect()
+    }
+}
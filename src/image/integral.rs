@@ -0,0 +1,107 @@
+use log::trace;
+
+use crate::crypt::operations::{clamp, widen, Divisor};
+use crate::crypt::{EncryptedImageData, ServerKeyType, Width};
+use crate::image::{EncryptedImageView, Size};
+use crate::progress;
+
+/// A summed-area table of an image, holding for every position the sum of all values above and to
+/// the left of it, so the sum over any box takes four additions regardless of its size.
+pub struct IntegralImage {
+    /// The size of the table, one larger than the image in both directions.
+    size: Size,
+    channels: usize,
+    /// The sums row by row, with a row and a column of zeros in front.
+    sums: Vec<EncryptedImageData>,
+    /// The width of the image's values, to reduce averages back to.
+    value_width: Width,
+}
+
+impl IntegralImage {
+    pub fn new(image: &EncryptedImageView, key: &ServerKeyType) -> Self {
+        let size = Size {
+            width: image.size.width + 1,
+            height: image.size.height + 1,
+        };
+        let channels = image.channel_count() as usize;
+        let count = image.size.width as u64 * image.size.height as u64;
+        // the sum of the whole image needs enough bits on top of a single value to count the pixels
+        let width = Width::for_bits(image.bit_depth.bits() + u64::BITS - count.leading_zeros());
+        let zero: EncryptedImageData = key.create_trivial_zero_radix(width.num_blocks());
+
+        let mut sums = vec![zero.clone(); size.width as usize * channels];
+        progress::expect(image.size.pixel_count());
+        for y in 0..image.size.height {
+            trace!("Row: {}", y);
+
+            let mut row_sums = vec![zero.clone(); channels];
+            sums.extend_from_slice(&row_sums);
+
+            for x in 0..image.size.width {
+                progress::step();
+
+                for (channel, value) in image.get_pixel(x, y).unwrap().into_iter().enumerate() {
+                    row_sums[channel] =
+                        key.add_parallelized(&row_sums[channel], &widen(value, width, key));
+
+                    let above = &sums
+                        [(y as usize * size.width as usize + x as usize + 1) * channels + channel];
+                    sums.push(key.add_parallelized(&row_sums[channel], above));
+                }
+            }
+        }
+
+        Self {
+            size,
+            channels,
+            sums,
+            value_width: Width::for_bits(image.bit_depth.bits()),
+        }
+    }
+
+    /// The sum of a channel over the box from `from` up to but excluding `to`.
+    pub fn box_sum(
+        &self,
+        from: (u16, u16),
+        to: (u16, u16),
+        channel: usize,
+        key: &ServerKeyType,
+    ) -> EncryptedImageData {
+        // the intermediate sum may wrap around, but the result fits the width again
+        key.sub_parallelized(
+            &key.add_parallelized(
+                self.sum(to.0, to.1, channel),
+                self.sum(from.0, from.1, channel),
+            ),
+            &key.add_parallelized(
+                self.sum(to.0, from.1, channel),
+                self.sum(from.0, to.1, channel),
+            ),
+        )
+    }
+
+    /// The average of a channel over the box from `from` up to but excluding `to`, rounded to the
+    /// nearest integer.
+    ///
+    /// Returns `None` if the area is too large to divide by exactly.
+    pub fn box_average(
+        &self,
+        from: (u16, u16),
+        to: (u16, u16),
+        channel: usize,
+        key: &ServerKeyType,
+    ) -> Option<EncryptedImageData> {
+        let area = (to.0 - from.0) as u64 * (to.1 - from.1) as u64;
+        let divisor = Divisor::new(area, self.value_width.max_value() as u128 * area as u128)?;
+        let sum = self.box_sum(from, to, channel, key);
+        if area == 1 {
+            return Some(clamp(&sum, self.value_width, key));
+        }
+
+        Some(clamp(&divisor.divide(&sum, key), self.value_width, key))
+    }
+
+    fn sum(&self, x: u16, y: u16, channel: usize) -> &EncryptedImageData {
+        &self.sums[(y as usize * self.size.width as usize + x as usize) * self.channels + channel]
+    }
+}
@@ -0,0 +1,103 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+/// Ancillary information about an image that is not needed to process it, but should be kept to
+/// display the result the same way as the original.
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize, Clone)]
+pub struct Metadata {
+    /// The gamma of the source image, scaled by 100000.
+    pub gamma: Option<u32>,
+    /// The white point and the red, green and blue primaries as x and y coordinates, scaled by
+    /// 100000.
+    pub chromaticities: Option<[(u32, u32); 4]>,
+    /// The rendering intent if the image is in the sRGB colour space.
+    pub srgb_intent: Option<u8>,
+    /// An embedded ICC colour profile.
+    pub icc_profile: Option<Vec<u8>>,
+    pub pixel_dimensions: Option<PixelDimensions>,
+    pub text: Vec<Text>,
+}
+
+impl Metadata {
+    /// Only the metadata describing how to display the pixels, like colour spaces and pixel sizes,
+    /// without text.
+    pub fn display_only(&self) -> Self {
+        Self {
+            text: Vec::new(),
+            ..self.clone()
+        }
+    }
+}
+
+/// The intended pixel size or aspect ratio.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy)]
+pub struct PixelDimensions {
+    pub x: u32,
+    pub y: u32,
+    /// Whether the values are pixels per metre, otherwise they only give the aspect ratio.
+    pub per_metre: bool,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone)]
+pub struct Text {
+    pub keyword: String,
+    pub text: String,
+    pub kind: TextKind,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy)]
+pub enum TextKind {
+    Latin1,
+    CompressedLatin1,
+    Utf8,
+}
+
+/// The metadata attached to an image.
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize, Clone)]
+pub enum AttachedMetadata<T> {
+    #[default]
+    None,
+    Plaintext(Metadata),
+    /// Serialised metadata, encrypted byte by byte.
+    Encrypted(Vec<T>),
+}
+
+impl<T: Clone> AttachedMetadata<T> {
+    pub fn view(&self) -> AttachedMetadata<&T> {
+        match self {
+            AttachedMetadata::None => AttachedMetadata::None,
+            AttachedMetadata::Plaintext(metadata) => AttachedMetadata::Plaintext(metadata.clone()),
+            AttachedMetadata::Encrypted(data) => AttachedMetadata::Encrypted(data.iter().collect()),
+        }
+    }
+}
+
+impl<T: Clone> AttachedMetadata<&T> {
+    /// Copy borrowed metadata, to attach it to an image computed from the one it is borrowed from.
+    pub fn cloned(&self) -> AttachedMetadata<T> {
+        match self {
+            AttachedMetadata::None => AttachedMetadata::None,
+            AttachedMetadata::Plaintext(metadata) => AttachedMetadata::Plaintext(metadata.clone()),
+            AttachedMetadata::Encrypted(data) => {
+                AttachedMetadata::Encrypted(data.iter().map(|&x| x.clone()).collect())
+            }
+        }
+    }
+}
+
+/// What to do with metadata when encrypting an image.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, ValueEnum)]
+pub enum MetadataPolicy {
+    /// Send the metadata describing how to display the image, like colour profiles, to the server
+    /// as plaintext and remove the rest, like text.
+    #[default]
+    Display,
+    /// Send metadata to the server as plaintext.
+    Plaintext,
+    /// Encrypt metadata along with the image, at the cost of a ciphertext for every byte.
+    Encrypted,
+    /// Remove metadata, so nothing about the image but its pixels reaches the server.
+    ///
+    /// Results are then written as srgb, whatever colour space the original was in.
+    Strip,
+}
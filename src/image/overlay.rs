@@ -0,0 +1,131 @@
+use serde::{Deserialize, Serialize};
+
+use crate::crypt::{Precision, ServerKeyType};
+use crate::image::pixel_operations::{apply_weight_map, Blend, WeightMap};
+use crate::image::{ColorType, EncryptedImage, Image, PlaintextImage, Size};
+
+/// A position-dependent effect computed from plaintext weights.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub enum Overlay {
+    /// Darken the image towards its corners, which are multiplied by one minus the strength.
+    Vignette { strength: f32 },
+    /// Change the weight linearly from one edge of the image to the opposite one, along a direction
+    /// in degrees counterclockwise from left to right.
+    Gradient {
+        angle: f32,
+        from: f32,
+        to: f32,
+        blend: Blend,
+    },
+    /// A map supplied by the client, stretched to the size of the image.
+    Mask { map: WeightMap, blend: Blend },
+}
+
+impl Overlay {
+    /// Whether a weight map supplied by the client has a weight for every pixel of its size.
+    pub fn is_valid(&self) -> bool {
+        match self {
+            Overlay::Mask { map, .. } => map.is_valid(),
+            Overlay::Vignette { .. } | Overlay::Gradient { .. } => true,
+        }
+    }
+}
+
+pub fn overlay(
+    image: &EncryptedImage,
+    overlay: &Overlay,
+    precision: Precision,
+    key: &ServerKeyType,
+) -> EncryptedImage {
+    let (map, blend) = match overlay {
+        Overlay::Vignette { strength } => (vignette(image.size, *strength), Blend::Multiply),
+        Overlay::Gradient {
+            angle,
+            from,
+            to,
+            blend,
+        } => (gradient(image.size, *angle, *from, *to), *blend),
+        Overlay::Mask { map, blend } => (map.clone(), *blend),
+    };
+
+    apply_weight_map(image, &map, blend, precision, key)
+}
+
+/// Create a mask from the brightness of an image, from zero for black to one for white.
+pub fn mask_from_image(image: &PlaintextImage) -> WeightMap {
+    let max_value = image.bit_depth.max_value() as f32;
+
+    WeightMap::from_fn(image.size, |x, y| brightness(image, x, y) / max_value)
+}
+
+/// Create a single-channel selection from an image, taken from its alpha channel if it has one and
+/// from its brightness otherwise.
+pub fn selection_from_image(image: &PlaintextImage) -> PlaintextImage {
+    let alpha = match image.color_type {
+        ColorType::GrayscaleAlpha => Some(1),
+        ColorType::Rgba => Some(3),
+        ColorType::Grayscale | ColorType::Rgb => None,
+    };
+    let data = (0..image.size.height)
+        .flat_map(|y| (0..image.size.width).map(move |x| (x, y)))
+        .map(|(x, y)| match alpha {
+            Some(channel) => *image.get_pixel(x, y).unwrap()[channel],
+            None => brightness(image, x, y).round() as u16,
+        })
+        .collect();
+
+    Image::new(
+        data,
+        image.size.width,
+        image.size.height,
+        ColorType::Grayscale,
+        image.bit_depth,
+    )
+}
+
+/// The average of the colour channels of a pixel.
+fn brightness(image: &PlaintextImage, x: u16, y: u16) -> f32 {
+    let color_channels = match image.color_type {
+        ColorType::Grayscale | ColorType::GrayscaleAlpha => 1,
+        ColorType::Rgb | ColorType::Rgba => 3,
+    };
+    let pixel = image.get_pixel(x, y).unwrap();
+
+    pixel[..color_channels]
+        .iter()
+        .map(|&&value| value as f32)
+        .sum::<f32>()
+        / color_channels as f32
+}
+
+fn vignette(size: Size, strength: f32) -> WeightMap {
+    let center = (
+        (size.width as f32 - 1.0) / 2.0,
+        (size.height as f32 - 1.0) / 2.0,
+    );
+    let max_distance = center.0.hypot(center.1).max(f32::EPSILON);
+
+    WeightMap::from_fn(size, |x, y| {
+        let distance = (x as f32 - center.0).hypot(y as f32 - center.1) / max_distance;
+        1.0 - strength * distance * distance
+    })
+}
+
+fn gradient(size: Size, angle: f32, from: f32, to: f32) -> WeightMap {
+    // y grows downwards, so counterclockwise angles go up
+    let direction = (angle.to_radians().cos(), -angle.to_radians().sin());
+    let project = |x: f32, y: f32| x * direction.0 + y * direction.1;
+    let corners = [
+        project(0.0, 0.0),
+        project(size.width as f32 - 1.0, 0.0),
+        project(0.0, size.height as f32 - 1.0),
+        project(size.width as f32 - 1.0, size.height as f32 - 1.0),
+    ];
+    let start = corners.into_iter().fold(f32::INFINITY, f32::min);
+    let length = (corners.into_iter().fold(f32::NEG_INFINITY, f32::max) - start).max(f32::EPSILON);
+
+    WeightMap::from_fn(size, |x, y| {
+        let position = (project(x as f32, y as f32) - start) / length;
+        from + (to - from) * position
+    })
+}
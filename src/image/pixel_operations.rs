@@ -1,75 +1,321 @@
+use std::fmt::{Debug, Formatter};
+
+use clap::ValueEnum;
 use log::trace;
+use serde::{Deserialize, Serialize};
+
+use crate::crypt::operations::{
+    average_three, invert_value, masked_blend, saturating_add, saturating_sub,
+    weight_multiplication,
+};
+use crate::crypt::{EncryptedImageData, Precision, ServerKeyType, Width};
+use crate::image::integral::IntegralImage;
+use crate::image::{ColorType, EncryptedImage, EncryptedImageView, Image, Size};
+use crate::progress;
+
+/// How the weights of a [`WeightMap`] are combined with the values of an image.
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize, Clone, Copy, ValueEnum)]
+pub enum Blend {
+    /// Multiply values by their weight.
+    #[default]
+    Multiply,
+    /// Add the weight as a fraction of the largest value, which can be negative.
+    Add,
+}
+
+/// A plaintext weight for every pixel of an image.
+#[derive(PartialEq, Serialize, Deserialize, Clone)]
+pub struct WeightMap {
+    pub size: Size,
+    /// The weights row by row.
+    pub weights: Vec<f32>,
+}
+
+impl WeightMap {
+    pub fn from_fn(size: Size, weight: impl Fn(u16, u16) -> f32) -> Self {
+        Self {
+            size,
+            weights: (0..size.height)
+                .flat_map(|y| (0..size.width).map(move |x| (x, y)))
+                .map(|(x, y)| weight(x, y))
+                .collect(),
+        }
+    }
+
+    /// Whether there is a weight for every pixel of the map, or none at all.
+    pub fn is_valid(&self) -> bool {
+        self.weights.is_empty() || self.weights.len() as u64 == self.size.pixel_count()
+    }
+
+    /// The weight at a pixel of an image of the given size, stretching the map to that size with
+    /// nearest-neighbour sampling.
+    pub fn sample(&self, x: u16, y: u16, size: Size) -> f32 {
+        if self.weights.is_empty() {
+            return 1.0;
+        }
 
-use crate::crypt::operations::{average_three, invert_u8};
-use crate::crypt::ServerKeyType;
-use crate::image::{ColorType, EncryptedImage, Image};
+        let (x, y) = (
+            (x as usize * self.size.width as usize / size.width.max(1) as usize),
+            (y as usize * self.size.height as usize / size.height.max(1) as usize),
+        );
+        self.weights[y * self.size.width as usize + x]
+    }
+}
+
+impl Debug for WeightMap {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Weight map ({:?})", self.size)
+    }
+}
+
+pub fn invert(image: &EncryptedImageView, key: &ServerKeyType) -> EncryptedImage {
+    let max_value = image.bit_depth.max_value();
+    progress::expect(image.size.pixel_count());
 
-pub fn invert(image: &EncryptedImage, key: &ServerKeyType) -> EncryptedImage {
     Image::new(
         match image.color_type {
             ColorType::GrayscaleAlpha | ColorType::Rgba => {
                 let mut inverted_data = Vec::with_capacity(
-                    (image.size.width * image.size.height * image.channel_count()) as usize,
+                    image.size.pixel_count() as usize * image.channel_count() as usize,
                 );
 
                 for y in 0..image.size.height {
                     for x in 0..image.size.width {
+                        progress::step();
+
                         let pixel = image.get_pixel(x, y).unwrap();
                         let mut pixel = pixel.iter();
 
                         // invert rgb/grayscale values
                         (0..image.channel_count() - 1).for_each(|_| {
-                            inverted_data.push(invert_u8(pixel.next().unwrap(), key));
+                            inverted_data.push(invert_value(pixel.next().unwrap(), max_value, key));
                         });
                         // copy alpha value
-                        inverted_data.push((*pixel.next().unwrap()).clone());
+                        inverted_data.push((**pixel.next().unwrap()).clone());
                     }
                 }
 
                 inverted_data
             }
-            ColorType::Grayscale | ColorType::Indexed | ColorType::Rgb => {
-                image.data.iter().map(|x| invert_u8(x, key)).collect()
-            }
+            ColorType::Grayscale | ColorType::Rgb => image
+                .data
+                .chunks(image.channel_count() as usize)
+                .flat_map(|pixel| {
+                    progress::step();
+                    pixel.iter().map(|x| invert_value(x, max_value, key))
+                })
+                .collect(),
         },
         image.size.width,
         image.size.height,
         image.color_type,
+        image.bit_depth,
     )
+    .with_metadata(image.metadata.cloned())
 }
 
-pub fn grayscale(image: &EncryptedImage, key: &ServerKeyType) -> Option<EncryptedImage> {
+pub fn grayscale(
+    image: &EncryptedImageView,
+    precision: Precision,
+    key: &ServerKeyType,
+) -> Option<EncryptedImage> {
     match image.color_type {
         ColorType::Rgb | ColorType::Rgba => {
-            let mut grayscale_data =
-                Vec::with_capacity((image.size.width * image.size.height) as usize);
+            let mut grayscale_data = Vec::with_capacity(image.size.pixel_count() as usize);
+            progress::expect(image.size.pixel_count());
 
             for y in 0..image.size.height {
                 for x in 0..image.size.width {
                     trace!("Pixel: ({}, {})", x, y);
+                    progress::step();
 
                     let pixel = image.get_pixel(x, y).unwrap();
 
                     // average rgb
-                    grayscale_data.push(average_three([pixel[0], pixel[1], pixel[2]], key));
+                    grayscale_data.push(average_three(
+                        [pixel[0], pixel[1], pixel[2]],
+                        precision,
+                        key,
+                    ));
                     // copy alpha
                     if image.color_type == ColorType::Rgba {
-                        grayscale_data.push(pixel[3].clone());
+                        grayscale_data.push((*pixel[3]).clone());
                     }
                 }
             }
 
-            Some(Image::new(
-                grayscale_data,
-                image.size.width,
-                image.size.height,
-                if image.color_type == ColorType::Rgba {
-                    ColorType::GrayscaleAlpha
-                } else {
-                    ColorType::Grayscale
-                },
-            ))
+            Some(
+                Image::new(
+                    grayscale_data,
+                    image.size.width,
+                    image.size.height,
+                    if image.color_type == ColorType::Rgba {
+                        ColorType::GrayscaleAlpha
+                    } else {
+                        ColorType::Grayscale
+                    },
+                    image.bit_depth,
+                )
+                .with_metadata(image.metadata.cloned()),
+            )
         }
         _ => None,
     }
 }
+
+/// Replace every value by the average of the values within the given radius, with the box cut off
+/// at the edges of the image.
+///
+/// Returns `None` if a box is too large to average exactly.
+pub fn box_blur(
+    image: &EncryptedImageView,
+    radius: u16,
+    key: &ServerKeyType,
+) -> Option<EncryptedImage> {
+    let integral = IntegralImage::new(image, key);
+    let mut data = Vec::with_capacity(image.data.len());
+    progress::expect(image.size.pixel_count());
+
+    for y in 0..image.size.height {
+        for x in 0..image.size.width {
+            trace!("Pixel: ({}, {})", x, y);
+            progress::step();
+
+            let from = (x.saturating_sub(radius), y.saturating_sub(radius));
+            let to = (
+                x.saturating_add(radius).min(image.size.width - 1) + 1,
+                y.saturating_add(radius).min(image.size.height - 1) + 1,
+            );
+            for channel in 0..image.channel_count() as usize {
+                data.push(integral.box_average(from, to, channel, key)?);
+            }
+        }
+    }
+
+    Some(
+        Image::new(
+            data,
+            image.size.width,
+            image.size.height,
+            image.color_type,
+            image.bit_depth,
+        )
+        .with_metadata(image.metadata.cloned()),
+    )
+}
+
+/// Combine every pixel of an image with the weight at its position, leaving alpha unchanged.
+///
+/// The map is stretched to the size of the image if the sizes differ.
+pub fn apply_weight_map(
+    image: &EncryptedImage,
+    map: &WeightMap,
+    blend: Blend,
+    precision: Precision,
+    key: &ServerKeyType,
+) -> EncryptedImage {
+    let max_value = image.bit_depth.max_value();
+    let mut data = Vec::with_capacity(image.data.len());
+    progress::expect(image.size.pixel_count());
+
+    for y in 0..image.size.height {
+        for x in 0..image.size.width {
+            trace!("Pixel: ({}, {})", x, y);
+            progress::step();
+
+            let weight = map.sample(x, y, image.size);
+            for (channel, value) in image.get_pixel(x, y).unwrap().into_iter().enumerate() {
+                data.push(if image.color_type.is_alpha(channel) {
+                    value.clone()
+                } else {
+                    match blend {
+                        Blend::Multiply => weight_multiplication(value, weight, precision, key),
+                        Blend::Add => add_offset(value, weight, max_value, key),
+                    }
+                });
+            }
+        }
+    }
+
+    Image::new(
+        data,
+        image.size.width,
+        image.size.height,
+        image.color_type,
+        image.bit_depth,
+    )
+    .with_metadata(image.metadata.clone())
+}
+
+/// Blend a processed image with the original by an encrypted single-channel mask, keeping the
+/// processed values where the mask is at its largest value and the original ones where it is zero.
+///
+/// Returns `None` if the images or the mask differ in size, or the images in colour type or bit
+/// depth.
+pub fn apply_mask(
+    original: &EncryptedImage,
+    processed: &EncryptedImage,
+    mask: &EncryptedImage,
+    key: &ServerKeyType,
+) -> Option<EncryptedImage> {
+    if original.size != processed.size
+        || original.size != mask.size
+        || original.color_type != processed.color_type
+        || original.bit_depth != processed.bit_depth
+    {
+        return None;
+    }
+
+    let max_value = mask.bit_depth.max_value();
+    let mut data = Vec::with_capacity(original.data.len());
+    progress::expect(original.size.pixel_count());
+
+    for y in 0..original.size.height {
+        for x in 0..original.size.width {
+            trace!("Pixel: ({}, {})", x, y);
+            progress::step();
+
+            let weight = mask.get_pixel(x, y).unwrap()[0];
+            let pixel = original.get_pixel(x, y).unwrap();
+            let processed_pixel = processed.get_pixel(x, y).unwrap();
+
+            data.extend(
+                processed_pixel
+                    .into_iter()
+                    .zip(pixel)
+                    .map(|(a, b)| masked_blend(a, b, weight, max_value, key)),
+            );
+        }
+    }
+
+    Some(
+        Image::new(
+            data,
+            original.size.width,
+            original.size.height,
+            original.color_type,
+            original.bit_depth,
+        )
+        .with_metadata(original.metadata.clone()),
+    )
+}
+
+/// Add a fraction of the largest value to a value, saturating at both ends of the range.
+fn add_offset(
+    x: &EncryptedImageData,
+    fraction: f32,
+    max_value: u16,
+    key: &ServerKeyType,
+) -> EncryptedImageData {
+    let offset = (fraction.clamp(-1.0, 1.0) * max_value as f32).round();
+    let magnitude: EncryptedImageData =
+        key.create_trivial_radix(offset.abs() as u64, Width::of(x).num_blocks());
+
+    if offset == 0.0 {
+        x.clone()
+    } else if offset > 0.0 {
+        saturating_add(x, &magnitude, key)
+    } else {
+        saturating_sub(x, &magnitude, key)
+    }
+}
@@ -3,8 +3,10 @@ use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 
 use crate::crypt::operations::bicubic_interpolation;
-use crate::crypt::ServerKeyType;
+use crate::crypt::{Precision, ServerKeyType};
+use crate::image::integral::IntegralImage;
 use crate::image::{EncryptedImage, Image, Size};
+use crate::progress;
 
 #[derive(Debug)]
 struct Scale {
@@ -25,30 +27,67 @@ impl Scale {
 pub enum InterpolationType {
     Nearest,
     Bilinear,
+    /// Average all pixels an output pixel covers, which suits downscaling.
+    Area,
 }
 
+/// Returns `None` if the areas to average are too large to divide by exactly.
 pub fn rescale(
     image: &EncryptedImage,
     key: &ServerKeyType,
     new_size: Size,
     interpolation_type: InterpolationType,
-) -> EncryptedImage {
+    precision: Precision,
+) -> Option<EncryptedImage> {
     match interpolation_type {
-        InterpolationType::Nearest => nearest(image, new_size),
-        InterpolationType::Bilinear => bilinear(image, key, new_size),
+        InterpolationType::Nearest => Some(nearest(image, new_size)),
+        InterpolationType::Bilinear => Some(bilinear(image, key, new_size, precision)),
+        InterpolationType::Area => area(image, key, new_size),
+    }
+}
+
+/// Cut out the area of the given size from the given x and y coordinates.
+///
+/// Returns `None` if the area is empty or does not fit the image.
+pub fn crop(image: &EncryptedImage, x: u16, y: u16, size: Size) -> Option<EncryptedImage> {
+    if size.width == 0
+        || size.height == 0
+        || x as u32 + size.width as u32 > image.size.width as u32
+        || y as u32 + size.height as u32 > image.size.height as u32
+    {
+        return None;
+    }
+
+    let mut cropped_data =
+        Vec::with_capacity(size.pixel_count() as usize * image.channel_count() as usize);
+    for row in y..y + size.height {
+        for column in x..x + size.width {
+            cropped_data.extend(image.get_pixel(column, row).unwrap().into_iter().cloned());
+        }
     }
+
+    Some(
+        Image::new(
+            cropped_data,
+            size.width,
+            size.height,
+            image.color_type,
+            image.bit_depth,
+        )
+        .with_metadata(image.metadata.clone()),
+    )
 }
 
 fn nearest(image: &EncryptedImage, new_size: Size) -> EncryptedImage {
     let scale = Scale::from_sizes(&image.size, &new_size);
     let mut rescaled_data =
-        Vec::with_capacity((new_size.width * new_size.height * image.channel_count()) as usize);
+        Vec::with_capacity(new_size.pixel_count() as usize * image.channel_count() as usize);
 
     for y in 0..new_size.height {
         for x in 0..new_size.width {
             let (x, y) = (
-                (x as f32 * scale.width) as u16,
-                (y as f32 * scale.height) as u16,
+                ((x as f32 * scale.width) as u16).min(image.size.width - 1),
+                ((y as f32 * scale.height) as u16).min(image.size.height - 1),
             );
             let pixel = image.get_pixel(x, y);
             rescaled_data.extend(pixel.unwrap().into_iter().cloned());
@@ -60,13 +99,21 @@ fn nearest(image: &EncryptedImage, new_size: Size) -> EncryptedImage {
         new_size.width,
         new_size.height,
         image.color_type,
+        image.bit_depth,
     )
+    .with_metadata(image.metadata.clone())
 }
 
-fn bilinear(image: &EncryptedImage, key: &ServerKeyType, new_size: Size) -> EncryptedImage {
+fn bilinear(
+    image: &EncryptedImage,
+    key: &ServerKeyType,
+    new_size: Size,
+    precision: Precision,
+) -> EncryptedImage {
     let scale = Scale::from_sizes(&image.size.minus_one(), &new_size.minus_one());
     let mut rescaled_data =
-        Vec::with_capacity((new_size.width * new_size.height * image.channel_count()) as usize);
+        Vec::with_capacity(new_size.pixel_count() as usize * image.channel_count() as usize);
+    progress::expect(new_size.pixel_count());
 
     for y in 0..new_size.height {
         for x in 0..new_size.width {
@@ -84,11 +131,19 @@ fn bilinear(image: &EncryptedImage, key: &ServerKeyType, new_size: Size) -> Encr
             // x_bounds.0 |                 | x_bounds.1
 
             trace!("Pixel: ({}, {})", x, y);
+            progress::step();
 
             let (x, y) = (x as f32 * scale.width, y as f32 * scale.height);
+            // the last pixels are interpolated towards themselves
             let (x_bounds, y_bounds) = (
-                (x.floor() as u16, x.ceil() as u16),
-                (y.floor() as u16, y.ceil() as u16),
+                (
+                    x.floor() as u16,
+                    (x.ceil() as u16).min(image.size.width - 1),
+                ),
+                (
+                    y.floor() as u16,
+                    (y.ceil() as u16).min(image.size.height - 1),
+                ),
             );
             let (x_weight, y_weight) = (x - x_bounds.0 as f32, y - y_bounds.0 as f32);
             let (a, b, c, d) = (
@@ -110,6 +165,7 @@ fn bilinear(image: &EncryptedImage, key: &ServerKeyType, new_size: Size) -> Encr
                     d[i].clone(),
                     x_weight,
                     y_weight,
+                    precision,
                     key.clone(),
                 ));
             }
@@ -122,5 +178,50 @@ fn bilinear(image: &EncryptedImage, key: &ServerKeyType, new_size: Size) -> Encr
         new_size.width,
         new_size.height,
         image.color_type,
+        image.bit_depth,
+    )
+    .with_metadata(image.metadata.clone())
+}
+
+fn area(image: &EncryptedImage, key: &ServerKeyType, new_size: Size) -> Option<EncryptedImage> {
+    let integral = IntegralImage::new(&image.view(), key);
+    // the source pixels an output pixel covers, including partly covered ones
+    let bounds = |x: u16, from: u16, to: u16| {
+        let (x, from, to) = (x as u32, from as u32, to as u32);
+        ((x * from / to) as u16, ((x + 1) * from).div_ceil(to) as u16)
+    };
+    let mut rescaled_data =
+        Vec::with_capacity(new_size.pixel_count() as usize * image.channel_count() as usize);
+    progress::expect(new_size.pixel_count());
+
+    for y in 0..new_size.height {
+        for x in 0..new_size.width {
+            trace!("Pixel: ({}, {})", x, y);
+            progress::step();
+
+            let (x_bounds, y_bounds) = (
+                bounds(x, image.size.width, new_size.width),
+                bounds(y, image.size.height, new_size.height),
+            );
+            for channel in 0..image.channel_count() as usize {
+                rescaled_data.push(integral.box_average(
+                    (x_bounds.0, y_bounds.0),
+                    (x_bounds.1, y_bounds.1),
+                    channel,
+                    key,
+                )?);
+            }
+        }
+    }
+
+    Some(
+        Image::new(
+            rescaled_data,
+            new_size.width,
+            new_size.height,
+            image.color_type,
+            image.bit_depth,
+        )
+        .with_metadata(image.metadata.clone()),
     )
 }
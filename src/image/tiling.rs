@@ -0,0 +1,371 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+
+use crate::image::{Image, Size};
+use crate::progress;
+
+/// The width and height of the area of an image processed at once, not counting its halo.
+pub const TILE_SIZE: u16 = 32;
+/// How many times larger than their halo tiles are at least, so the work done on the halos stays
+/// small even for large neighbourhoods.
+const HALO_RATIO: u16 = 4;
+
+/// An area of an image processed on its own.
+#[derive(Debug, PartialEq, Eq)]
+struct Tile {
+    /// The top left corner of the area.
+    x: u16,
+    y: u16,
+    size: Size,
+    /// The top left corner of the area with its halo, which is clipped to the image.
+    padded_x: u16,
+    padded_y: u16,
+    padded_size: Size,
+}
+
+/// Apply an operation to tiles of an image in parallel and stitch the results together, with a halo
+/// of the given number of pixels around every tile for operations that look at neighbouring pixels.
+///
+/// The operation has to keep the size of the image, only look at pixels within the halo and not
+/// depend on where the tile is, but may change the colour type. Returns `None` if it fails on any
+/// tile.
+///
+/// Tiles borrow the samples of the image and are handed to a fixed number of workers one row of
+/// tiles at a time, so besides the stitched rows only the results of the current row are held.
+/// Failed tiles are not retried, and a cancelled job starts over instead of resuming from the rows
+/// stitched so far.
+pub fn process_tiled<T: Clone + Send + Sync>(
+    image: &Image<T>,
+    halo: u16,
+    operation: impl Fn(&Image<&T>) -> Option<Image<T>> + Sync,
+) -> Option<Image<T>> {
+    let tile_size = TILE_SIZE.max(halo.saturating_mul(HALO_RATIO));
+    let tiles = tiles(image.size, tile_size, halo);
+    // small images are processed as a whole
+    if tiles.len() <= 1 {
+        return operation(&image.view());
+    }
+
+    let columns = image.size.width.div_ceil(tile_size) as usize;
+    let workers = thread::available_parallelism()
+        .map_or(1, |count| count.get())
+        .min(columns);
+    // steps on the workers count towards the job and stop when it is cancelled
+    let progress = progress::current();
+    // the pixels in the halos are processed more than once, but only counted once
+    progress::expect(image.size.pixel_count());
+
+    let (data, color_type, bit_depth) = thread::scope(|scope| {
+        // a tile is only taken from the queue by a worker that is ready to process it
+        let (tile_sender, tile_receiver) = mpsc::sync_channel::<(usize, &Tile)>(workers);
+        let tile_receiver = Arc::new(Mutex::new(tile_receiver));
+        let (result_sender, result_receiver) = mpsc::channel();
+
+        for _ in 0..workers {
+            let (tile_receiver, result_sender) = (tile_receiver.clone(), result_sender.clone());
+            let (progress, operation) = (progress.clone(), &operation);
+            scope.spawn(move || {
+                progress::attach(progress, || loop {
+                    let received = tile_receiver
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .recv();
+                    let Ok((column, tile)) = received else {
+                        break;
+                    };
+
+                    // a failing tile is reported instead of leaving the row waiting for it
+                    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                        let padded = cut(image, tile.padded_x, tile.padded_y, tile.padded_size);
+                        progress::scaled(tile.size.pixel_count(), || operation(&padded))
+                            .filter(|result| result.size == tile.padded_size)
+                            .map(|result| {
+                                interior(
+                                    result,
+                                    tile.x - tile.padded_x,
+                                    tile.y - tile.padded_y,
+                                    tile.size,
+                                )
+                            })
+                    }));
+                    // the receiver is only gone once the image failed
+                    let _ = result_sender.send((column, result));
+                })
+            });
+        }
+
+        let mut data = Vec::new();
+        let mut format = None;
+        for row in tiles.chunks(columns) {
+            for tile in row.iter().enumerate() {
+                tile_sender.send(tile).ok()?;
+            }
+
+            let mut results = (0..row.len()).map(|_| None).collect::<Vec<_>>();
+            for _ in 0..row.len() {
+                match result_receiver.recv().ok()? {
+                    (column, Ok(result)) => results[column] = Some(result?),
+                    // keeps the payload of a cancelled job, the workers stop once the queue is
+                    // dropped
+                    (_, Err(payload)) => panic::resume_unwind(payload),
+                }
+            }
+
+            // the rows of the image are the rows of every tile in a row of tiles
+            let results = results.into_iter().collect::<Option<Vec<_>>>()?;
+            let height = results.first()?.size.height;
+            format.get_or_insert((results[0].color_type, results[0].bit_depth));
+            let mut results = results
+                .into_iter()
+                .map(|tile| {
+                    let row_length = tile.size.width as usize * tile.channel_count() as usize;
+                    (row_length, tile.data.into_iter())
+                })
+                .collect::<Vec<_>>();
+            for _ in 0..height {
+                for (row_length, samples) in &mut results {
+                    data.extend(samples.by_ref().take(*row_length));
+                }
+            }
+        }
+
+        format.map(|(color_type, bit_depth)| (data, color_type, bit_depth))
+    })?;
+
+    Some(
+        Image::new(
+            data,
+            image.size.width,
+            image.size.height,
+            color_type,
+            bit_depth,
+        )
+        .with_metadata(image.metadata.clone()),
+    )
+}
+
+fn tiles(size: Size, tile_size: u16, halo: u16) -> Vec<Tile> {
+    let mut tiles = Vec::new();
+
+    for y in (0..size.height).step_by(tile_size as usize) {
+        for x in (0..size.width).step_by(tile_size as usize) {
+            let tile_size = Size {
+                width: tile_size.min(size.width - x),
+                height: tile_size.min(size.height - y),
+            };
+            let (padded_x, padded_y) = (x.saturating_sub(halo), y.saturating_sub(halo));
+            let end_x = (x as u32 + tile_size.width as u32 + halo as u32).min(size.width as u32);
+            let end_y = (y as u32 + tile_size.height as u32 + halo as u32).min(size.height as u32);
+
+            tiles.push(Tile {
+                x,
+                y,
+                size: tile_size,
+                padded_x,
+                padded_y,
+                padded_size: Size {
+                    width: end_x as u16 - padded_x,
+                    height: end_y as u16 - padded_y,
+                },
+            });
+        }
+    }
+
+    tiles
+}
+
+/// Borrow an area of an image, which has to lie within it.
+///
+/// Unlike cropping, the metadata is left out, as it is only attached to the stitched image.
+fn cut<T: Clone>(image: &Image<T>, x: u16, y: u16, size: Size) -> Image<&T> {
+    let channels = image.channel_count() as usize;
+    let mut data = Vec::with_capacity(size.pixel_count() as usize * channels);
+
+    for row in y..y + size.height {
+        let start = (row as usize * image.size.width as usize + x as usize) * channels;
+        data.extend(&image.data[start..start + size.width as usize * channels]);
+    }
+
+    Image::new(
+        data,
+        size.width,
+        size.height,
+        image.color_type,
+        image.bit_depth,
+    )
+}
+
+/// Keep only an area of a processed tile, moving its samples instead of copying them.
+fn interior<T: Clone>(image: Image<T>, x: u16, y: u16, size: Size) -> Image<T> {
+    let channels = image.channel_count() as usize;
+    let row_length = image.size.width as usize * channels;
+    let (columns, rows) = (
+        x as usize * channels..(x + size.width) as usize * channels,
+        y as usize..(y + size.height) as usize,
+    );
+    let data = image
+        .data
+        .into_iter()
+        .enumerate()
+        .filter(|(index, _)| {
+            rows.contains(&(index / row_length)) && columns.contains(&(index % row_length))
+        })
+        .map(|(_, sample)| sample)
+        .collect();
+
+    Image::new(
+        data,
+        size.width,
+        size.height,
+        image.color_type,
+        image.bit_depth,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::{BitDepth, ColorType, PlaintextImage};
+
+    fn gradient(width: u16, height: u16) -> PlaintextImage {
+        let length = width as usize * height as usize * 3;
+        Image::new(
+            (0..length as u16).collect(),
+            width,
+            height,
+            ColorType::Rgb,
+            BitDepth::Sixteen,
+        )
+    }
+
+    /// Sum every sample with the samples of the same channel in the pixels next to it.
+    fn horizontal_sum(image: &Image<&u16>) -> Option<PlaintextImage> {
+        let width = image.size.width;
+        let data = (0..image.size.height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .flat_map(|(x, y)| {
+                (0..3).map(move |channel| {
+                    (x.saturating_sub(1)..(x + 2).min(width))
+                        .map(|x| **image.get_pixel(x, y).unwrap()[channel])
+                        .fold(0_u16, u16::wrapping_add)
+                })
+            })
+            .collect();
+
+        Some(Image::new(
+            data,
+            width,
+            image.size.height,
+            image.color_type,
+            image.bit_depth,
+        ))
+    }
+
+    #[test]
+    fn clips_halos_to_the_image() {
+        let tiles = tiles(
+            Size {
+                width: 70,
+                height: 40,
+            },
+            32,
+            3,
+        );
+
+        assert_eq!(tiles.len(), 6);
+        assert_eq!(
+            tiles[0],
+            Tile {
+                x: 0,
+                y: 0,
+                size: Size {
+                    width: 32,
+                    height: 32
+                },
+                padded_x: 0,
+                padded_y: 0,
+                padded_size: Size {
+                    width: 35,
+                    height: 35
+                },
+            }
+        );
+        assert_eq!(
+            tiles[1].padded_size,
+            Size {
+                width: 38,
+                height: 35
+            }
+        );
+        assert_eq!(
+            tiles[5],
+            Tile {
+                x: 64,
+                y: 32,
+                size: Size {
+                    width: 6,
+                    height: 8
+                },
+                padded_x: 61,
+                padded_y: 29,
+                padded_size: Size {
+                    width: 9,
+                    height: 11
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn stitches_tiles_like_the_whole_image() {
+        let image = gradient(70, 40);
+
+        let copied = process_tiled(&image, 0, |tile| {
+            Some(Image::new(
+                tile.data.iter().map(|&&x| x).collect(),
+                tile.size.width,
+                tile.size.height,
+                tile.color_type,
+                tile.bit_depth,
+            ))
+        });
+        assert_eq!(copied, Some(image.clone()));
+
+        let summed = process_tiled(&image, 1, horizontal_sum);
+        assert_eq!(summed, horizontal_sum(&image.view()));
+    }
+
+    #[test]
+    fn changes_the_colour_type() {
+        let image = gradient(40, 40);
+
+        let red = process_tiled(&image, 0, |tile| {
+            Some(Image::new(
+                tile.data.iter().step_by(3).map(|&&x| x).collect(),
+                tile.size.width,
+                tile.size.height,
+                ColorType::Grayscale,
+                tile.bit_depth,
+            ))
+        })
+        .unwrap();
+        assert_eq!(red.color_type, ColorType::Grayscale);
+        assert_eq!(
+            red.data,
+            image.data.iter().step_by(3).copied().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn fails_if_any_tile_fails() {
+        let image = gradient(70, 40);
+
+        assert_eq!(
+            process_tiled(&image, 0, |tile| (tile.size.width > 10)
+                .then(|| gradient(tile.size.width, tile.size.height))),
+            None
+        );
+    }
+}
@@ -1,15 +1,27 @@
 use std::error::Error;
+use std::io::{self, Write};
 use std::path::Path;
+use std::thread;
+use std::time::Duration;
 
-use clap::Parser;
 use log::info;
 
-use crate::arguments::{Arguments, Command, LoadCommand};
+use crate::arguments::{
+    Arguments, BlurCommand, Command, CompareCommand, DecryptCommand, DifferenceCommand,
+    EncryptCommand, GradientCommand, JobCommand, LoadCommand, MaskCommand, MatchCommand,
+    PipelineCommand, PosteriseCommand, SelectCommand, VignetteCommand,
+};
 use crate::client::Client;
 use crate::crypt::key::load_or_generate_keys;
+use crate::crypt::Precision;
+use crate::image::animation::Animation;
+use crate::image::comparison::{Measurement, Metric, ScoreMap};
+use crate::image::overlay::{mask_from_image, selection_from_image, Overlay};
 use crate::image::rescaling::InterpolationType;
-use crate::image::{EncryptedImage, Image, Size};
-use crate::message::Message;
+use crate::image::{BitDepth, ColorType, EncryptedImage, Image, Size};
+use crate::message::{Message, Request};
+use crate::pipeline::Operation;
+use crate::progress::ProgressReport;
 use crate::server::Server;
 
 mod arguments;
@@ -18,28 +30,61 @@ mod crypt;
 mod exploration;
 mod image;
 mod message;
+mod pipeline;
+mod progress;
+mod protocol;
 mod server;
 
+/// How often to ask the server about a running job.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
 fn main() -> Result<(), Box<dyn Error>> {
     pretty_env_logger::init();
 
-    let arguments = Arguments::parse();
+    let arguments = Arguments::parse_checked();
     let address = arguments.address;
+    let keep_encrypted = arguments.keep_encrypted;
+    let precision = Precision::new(arguments.precision);
+    let (slot, store_as, in_place) = (arguments.slot, arguments.store_as, arguments.in_place);
+    let detach = arguments.detach;
+    // messages apply to the default slot unless another one is given
+    let slotted = |message: Message| Request::new(message).with_slot(slot.clone());
+    // results of in-place operations replace the image stored on the server instead of being sent
+    let request = |message: Message| {
+        slotted(message)
+            .with_store_as(store_as.clone())
+            .with_in_place(in_place)
+    };
 
     match arguments.command {
         Command::Server => {
-            let (_, server_key) = load_or_generate_keys(
-                Path::new("data/keys/client"),
-                Path::new("data/keys/server"),
-            )?;
-            Server::new(server_key).start(address.as_str())?;
+            Server::new().start(address.as_str())?;
         }
         command => {
-            let (client_key, _) = load_or_generate_keys(
-                Path::new("data/keys/client"),
-                Path::new("data/keys/server"),
-            )?;
-            let client = Client::new(address.as_str(), client_key);
+            let server_key_path = Path::new("data/keys/server");
+            let (client_key, server_key) =
+                load_or_generate_keys(Path::new("data/keys/client"), server_key_path)?;
+            let mut client = Client::new(
+                address.as_str(),
+                client_key,
+                server_key,
+                server_key_path,
+                arguments.metadata,
+            );
+            // incompatible servers are detected before sending them anything else
+            let server = match command {
+                Command::Encrypt(_) | Command::Decrypt(_) => None,
+                _ => Some(client.hello()?),
+            };
+            // only the server key is sent, the client key never leaves this machine
+            if !matches!(
+                command,
+                Command::Ping | Command::Shutdown | Command::Encrypt(_) | Command::Decrypt(_)
+            ) {
+                client.open_session(Path::new("data/keys/session"))?;
+            }
+            // operations run as jobs so their progress can be shown
+            let run = |request: Request| submit(&client, request, detach);
 
             match command {
                 Command::Ping => {
@@ -51,14 +96,44 @@ fn main() -> Result<(), Box<dyn Error>> {
                 Command::Shutdown => {
                     client.send_message(Message::Shutdown)?;
                 }
+                Command::Encrypt(EncryptCommand { input, output }) => {
+                    let image = Image::load(input.as_path())?;
+
+                    client
+                        .encrypt_image(&image)
+                        .save_encrypted(output.as_path(), client.key_digest()?)?;
+                }
+                Command::Decrypt(DecryptCommand { input, output }) => {
+                    let image =
+                        EncryptedImage::load_encrypted(input.as_path(), client.key_digest()?)?;
+
+                    decrypt_and_save(&client, &image, output.as_path())?;
+                }
                 Command::Load(LoadCommand { file }) => {
-                    let image = Image::load(file.as_path())?;
+                    if EncryptedImage::is_encrypted_file(file.as_path())? {
+                        client.send_request(slotted(Message::Image(
+                            EncryptedImage::load_encrypted(file.as_path(), client.key_digest()?)?,
+                        )))?;
+                    } else {
+                        let animation = Animation::load(file.as_path())?;
 
-                    client.send_message(Message::Image(client.encrypt_image(&image)))?;
+                        if animation.is_animated() {
+                            client.send_request(slotted(Message::Animation(
+                                client.encrypt_animation(&animation),
+                            )))?;
+                        } else {
+                            let image =
+                                animation.into_first_frame().ok_or("Image has no frames")?;
+                            // still images are encrypted row by row while sending them
+                            client.send_image(&image, slotted)?;
+                        }
+                    }
                 }
                 Command::Rescale(rescale_command) => {
                     let interpolation_type = if rescale_command.bilinear {
                         InterpolationType::Bilinear
+                    } else if rescale_command.area {
+                        InterpolationType::Area
                     } else {
                         InterpolationType::Nearest
                     };
@@ -67,35 +142,156 @@ fn main() -> Result<(), Box<dyn Error>> {
                         rescale_command.width, rescale_command.height
                     );
 
-                    let answer = client.send_message(Message::Rescale(
+                    let answer = run(request(Message::Rescale(
                         Size {
                             width: rescale_command.width,
                             height: rescale_command.height,
                         },
                         interpolation_type,
-                    ))?;
-                    if let Some(Message::Image(image)) = answer {
-                        decrypt_and_save(
-                            &client,
-                            &image,
-                            format!(
-                                "data/output/rescaled-{:?}-{}x{}.png",
-                                interpolation_type, rescale_command.width, rescale_command.height
-                            )
-                            .as_str(),
-                        )?;
-                    }
+                        precision,
+                    )))?;
+                    save_result(
+                        &client,
+                        answer,
+                        format!(
+                            "data/output/rescaled-{:?}-{}x{}.png",
+                            interpolation_type, rescale_command.width, rescale_command.height
+                        )
+                        .as_str(),
+                        keep_encrypted,
+                    )?;
                 }
                 Command::Invert => {
-                    let answer = client.send_message(Message::Invert)?;
-                    if let Some(Message::Image(image)) = answer {
-                        decrypt_and_save(&client, &image, "data/output/inverted.png")?;
-                    }
+                    let answer = run(request(Message::Invert))?;
+                    save_result(&client, answer, "data/output/inverted.png", keep_encrypted)?;
                 }
                 Command::Grayscale => {
-                    let answer = client.send_message(Message::Grayscale)?;
-                    if let Some(Message::Image(image)) = answer {
-                        decrypt_and_save(&client, &image, "data/output/grayscale.png")?;
+                    let answer = run(request(Message::Grayscale(precision)))?;
+                    save_result(&client, answer, "data/output/grayscale.png", keep_encrypted)?;
+                }
+                Command::Posterise(PosteriseCommand { levels, dither }) => {
+                    info!(
+                        "Posterising to {} levels with {:?} dithering...",
+                        levels, dither
+                    );
+
+                    let answer = run(request(Message::Posterise(levels, dither)))?;
+                    save_result(
+                        &client,
+                        answer,
+                        format!("data/output/posterised-{}-{:?}.png", levels, dither).as_str(),
+                        keep_encrypted,
+                    )?;
+                }
+                Command::Blur(BlurCommand { radius }) => {
+                    let answer = run(request(Message::Blur(radius)))?;
+                    save_result(&client, answer, "data/output/blurred.png", keep_encrypted)?;
+                }
+                Command::Vignette(VignetteCommand { strength }) => {
+                    let answer = run(request(Message::Overlay(
+                        Overlay::Vignette { strength },
+                        precision,
+                    )))?;
+                    save_result(&client, answer, "data/output/vignette.png", keep_encrypted)?;
+                }
+                Command::Gradient(GradientCommand {
+                    angle,
+                    from,
+                    to,
+                    blend,
+                }) => {
+                    let answer = run(request(Message::Overlay(
+                        Overlay::Gradient {
+                            angle,
+                            from,
+                            to,
+                            blend,
+                        },
+                        precision,
+                    )))?;
+                    save_result(&client, answer, "data/output/gradient.png", keep_encrypted)?;
+                }
+                Command::Mask(MaskCommand { file, blend }) => {
+                    let map = mask_from_image(&Image::load(&file)?);
+                    let answer = run(request(Message::Overlay(
+                        Overlay::Mask { map, blend },
+                        precision,
+                    )))?;
+                    save_result(&client, answer, "data/output/masked.png", keep_encrypted)?;
+                }
+                Command::Select(SelectCommand { file }) => {
+                    let mask = match file {
+                        Some(file) => {
+                            Some(client.encrypt_image(&selection_from_image(&Image::load(&file)?)))
+                        }
+                        None => None,
+                    };
+                    client.send_request(slotted(Message::Selection(mask)))?;
+                }
+                Command::Pipeline(PipelineCommand { operations }) => {
+                    if let Some(server) = &server {
+                        for step in &operations {
+                            let name = step.split_once('=').map_or(step.as_str(), |(name, _)| name);
+                            if !server.operations.iter().any(|operation| operation == name) {
+                                return Err(format!("The server does not support {}", name).into());
+                            }
+                        }
+                    }
+                    let operations = operations
+                        .iter()
+                        .map(|step| Operation::parse(step, precision))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    let answer = run(request(Message::Pipeline(operations)))?;
+                    save_result(&client, answer, "data/output/pipeline.png", keep_encrypted)?;
+                }
+                Command::Fetch => {
+                    let answer = client.send_request(slotted(Message::Fetch))?;
+                    save_result(&client, answer, "data/output/fetched.png", keep_encrypted)?;
+                }
+                Command::Slots => {
+                    if let Some(Message::Slots(names)) = client.send_message(Message::ListSlots)? {
+                        info!("Slots: {}", names.join(", "));
+                    }
+                }
+                Command::Delete => {
+                    client.send_request(slotted(Message::Delete))?;
+                }
+                Command::Difference(DifferenceCommand { other }) => {
+                    let answer = run(request(Message::Difference(other)))?;
+                    save_result(
+                        &client,
+                        answer,
+                        "data/output/difference.png",
+                        keep_encrypted,
+                    )?;
+                }
+                Command::Compare(CompareCommand { other, metric }) => {
+                    let answer = run(slotted(Message::Measure(metric, other)))?;
+                    save_result(&client, answer, "data/output/compared.png", keep_encrypted)?;
+                }
+                Command::Match(MatchCommand { template, locate }) => {
+                    let answer = run(slotted(Message::MatchTemplate(
+                        load_image(&client, &template)?,
+                        locate,
+                    )))?;
+                    save_result(&client, answer, "data/output/matches.png", keep_encrypted)?;
+                }
+                Command::Status(JobCommand { id }) => {
+                    match client.send_message(Message::JobStatus(id))? {
+                        Some(Message::Progress(report)) => info!("{}", describe_progress(&report)),
+                        Some(Message::NoJob) => return Err(format!("No job {}", id).into()),
+                        answer => {
+                            save_result(&client, answer, "data/output/job.png", keep_encrypted)?
+                        }
+                    }
+                }
+                Command::Wait(JobCommand { id }) => {
+                    let answer = wait_for_job(&client, id)?;
+                    save_result(&client, answer, "data/output/job.png", keep_encrypted)?;
+                }
+                Command::Cancel(JobCommand { id }) => {
+                    if let Some(Message::NoJob) = client.send_message(Message::Cancel(id))? {
+                        return Err(format!("No job {}", id).into());
                     }
                 }
                 Command::Server => unreachable!(),
@@ -106,15 +302,203 @@ fn main() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+/// Start a request as a job on the server and wait for its answer while showing its progress.
+///
+/// Returns no answer if the job is detached, after printing its ID.
+fn submit(
+    client: &Client,
+    request: Request,
+    detach: bool,
+) -> Result<Option<Message>, Box<dyn Error>> {
+    match client.send_request(request.in_background())? {
+        Some(Message::JobStarted(id)) if detach => {
+            println!("{}", id);
+
+            Ok(None)
+        }
+        Some(Message::JobStarted(id)) => wait_for_job(client, id),
+        answer => Ok(answer),
+    }
+}
+
+/// Poll a job until it finishes, drawing a progress bar on standard error.
+fn wait_for_job(client: &Client, id: u64) -> Result<Option<Message>, Box<dyn Error>> {
+    let mut shown = false;
+
+    loop {
+        match client.send_message(Message::JobStatus(id))? {
+            Some(Message::Progress(report)) => {
+                const BAR_LENGTH: u64 = 30;
+                let filled = (report.done * BAR_LENGTH)
+                    .checked_div(report.total)
+                    .unwrap_or(0)
+                    .min(BAR_LENGTH) as usize;
+                eprint!(
+                    "\r[{}{}] {}",
+                    "#".repeat(filled),
+                    "-".repeat(BAR_LENGTH as usize - filled),
+                    describe_progress(&report)
+                );
+                io::stderr().flush()?;
+                shown = true;
+
+                thread::sleep(POLL_INTERVAL);
+            }
+            answer => {
+                if shown {
+                    eprintln!();
+                }
+
+                return match answer {
+                    Some(Message::NoJob) => {
+                        Err(format!("Job {} was cancelled or does not exist", id).into())
+                    }
+                    answer => Ok(answer),
+                };
+            }
+        }
+    }
+}
+
+fn describe_progress(report: &ProgressReport) -> String {
+    let remaining = match report.remaining {
+        Some(remaining) => format!("{}s left", remaining.as_secs()),
+        None => "estimating time left".to_string(),
+    };
+
+    format!(
+        "{}/{} steps after {}s, {}",
+        report.done,
+        report.total,
+        report.elapsed.as_secs(),
+        remaining
+    )
+}
+
+/// Store a result received from the server, either decrypted or as an encrypted image file next
+/// to the given path.
+fn save_result(
+    client: &Client,
+    answer: Option<Message>,
+    path: &str,
+    keep_encrypted: bool,
+) -> Result<(), Box<dyn Error>> {
+    let path = Path::new(path);
+
+    match answer {
+        Some(Message::Image(image)) if keep_encrypted => {
+            let path = path.with_extension("fhe");
+            image.save_encrypted(&path, client.key_digest()?)?;
+            info!("Stored encrypted result in {:?}", path);
+
+            Ok(())
+        }
+        Some(Message::Image(image)) => decrypt_and_save(client, &image, path),
+        Some(Message::Stored) => {
+            info!("Stored result on the server");
+
+            Ok(())
+        }
+        Some(Message::Measurement(measurement)) => {
+            report_measurement(client, &measurement);
+
+            Ok(())
+        }
+        Some(Message::TemplateMatches(matches)) => {
+            save_matches(client, &matches, &path.with_file_name("matches.png"))
+        }
+        Some(Message::Animation(_)) if keep_encrypted => {
+            Err("Animations cannot be stored as encrypted image files".into())
+        }
+        Some(Message::Animation(animation)) => {
+            let decrypted_animation = client.decrypt_animation(&animation);
+            info!("Decrypted: {:?}", decrypted_animation);
+
+            decrypted_animation.save(path)
+        }
+        // the request succeeded without a result, or was detached as a job
+        None => Ok(()),
+        Some(Message::NoImage) => Err("The server holds no image to process".into()),
+        Some(Message::NoSession) => Err("The server does not know the session".into()),
+        Some(answer) => Err(format!("Unexpected answer {:?}", answer).into()),
+    }
+}
+
+fn report_measurement(client: &Client, measurement: &Measurement) {
+    let sum = client.decrypt_value(&measurement.sum);
+    match measurement.metric {
+        Metric::SumOfAbsoluteDifferences => info!("Sum of absolute differences: {}", sum),
+        Metric::MeanSquaredError => info!(
+            "Mean squared error: {}",
+            sum as f64 / measurement.count as f64
+        ),
+    }
+}
+
+/// Decrypt template matching scores, report the best match and store the scores as an image with
+/// the best matches brightest.
+fn save_matches(client: &Client, matches: &ScoreMap, path: &Path) -> Result<(), Box<dyn Error>> {
+    let scores = matches
+        .scores
+        .iter()
+        .map(|score| client.decrypt_value(score))
+        .collect::<Vec<u64>>();
+    let (best, worst) = (
+        scores.iter().copied().min().ok_or("No template matches")?,
+        scores.iter().copied().max().ok_or("No template matches")?,
+    );
+    let index = scores.iter().position(|&score| score == best).unwrap();
+    info!(
+        "Best match at ({}, {}) with a sum of absolute differences of {}",
+        index % matches.size.width as usize,
+        index / matches.size.width as usize,
+        best
+    );
+    if let Some((x, y)) = &matches.best_match {
+        info!(
+            "Best match located by the server at ({}, {})",
+            client.decrypt_value(x),
+            client.decrypt_value(y)
+        );
+    }
+
+    Image::new(
+        scores
+            .iter()
+            .map(|&score| match worst - best {
+                0 => u8::MAX as u16,
+                range => ((worst - score) * u8::MAX as u64 / range) as u16,
+            })
+            .collect(),
+        matches.size.width,
+        matches.size.height,
+        ColorType::Grayscale,
+        BitDepth::Eight,
+    )
+    .save(path)?;
+    info!("Stored scores in {:?}", path);
+
+    Ok(())
+}
+
+/// Load a still image to send to the server, encrypting it unless it is an encrypted image file.
+fn load_image(client: &Client, path: &Path) -> Result<EncryptedImage, Box<dyn Error>> {
+    if EncryptedImage::is_encrypted_file(path)? {
+        EncryptedImage::load_encrypted(path, client.key_digest()?)
+    } else {
+        Ok(client.encrypt_image(&Image::load(path)?))
+    }
+}
+
 fn decrypt_and_save(
     client: &Client,
     image: &EncryptedImage,
-    path: &str,
+    path: &Path,
 ) -> Result<(), Box<dyn Error>> {
     let decrypted_image = client.decrypt_image(image);
     info!("Decrypted: {:?}", decrypted_image);
 
-    decrypted_image.save(Path::new(path))?;
+    decrypted_image.save(path)?;
 
     Ok(())
 }
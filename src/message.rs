@@ -1,9 +1,41 @@
 use serde::{Deserialize, Serialize};
 
+use crate::crypt::digest::Digest;
+use crate::crypt::key::{SessionKey, SessionToken};
+use crate::crypt::{EncryptedImageData, Precision};
+use crate::image::animation::EncryptedAnimation;
+use crate::image::comparison::{Measurement, Metric, ScoreMap};
+use crate::image::dithering::Dither;
+use crate::image::overlay::Overlay;
 use crate::image::rescaling::InterpolationType;
 use crate::image::{EncryptedImage, Size};
+use crate::pipeline::Operation;
+use crate::progress::ProgressReport;
+use crate::protocol::Payload;
 
-#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// A message sent by a client, with where it applies and what happens to its result.
+///
+/// These are fields of the request rather than messages wrapping other messages, so a request
+/// never nests and is read without recursing. The server rejects requests that store their result
+/// both in place and in another slot, and requests for the status of a job or cancelling it that
+/// name a slot or a destination or are run as a job themselves.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Request {
+    /// The session to run the message in, which every message but the ones managing sessions
+    /// needs.
+    pub session: Option<SessionToken>,
+    /// The slot holding the image the message applies to, instead of the default one.
+    pub slot: Option<String>,
+    /// Replace the image in the slot with the result instead of sending it back.
+    pub in_place: bool,
+    /// Store the result in the named slot instead of sending it back.
+    pub store_as: Option<String>,
+    /// Run the message in the background, answering with the ID of the job right away.
+    pub job: bool,
+    pub message: Message,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub enum Message {
     /// Check if the server is alive.
     Ping,
@@ -11,23 +43,224 @@ pub enum Message {
     Pong,
     /// Shut down the server.
     Shutdown,
+    /// Register a new session with a server key, keeping the images and key of the client apart
+    /// from other clients.
+    Register(SessionKey),
+    /// Check if the session with the given token still exists and holds the server key with the
+    /// given digest.
+    Resume(SessionToken, Digest),
+    /// The token of a registered session.
+    Session(SessionToken),
+    /// There is no such session on the server.
+    NoSession,
     /// Send an image on the server to do operations on.
     Image(EncryptedImage),
-    /// Rescale the stored image to a new size using the given interpolation type.
-    Rescale(Size, InterpolationType),
+    /// Send an animation on the server to do operations on every frame of.
+    Animation(EncryptedAnimation),
+    /// Rescale the stored image to a new size using the given interpolation type and weight
+    /// precision.
+    Rescale(Size, InterpolationType, Precision),
     /// Invert the stored image.
     Invert,
-    /// Turn the stored image into grayscale.
-    Grayscale,
+    /// Turn the stored image into grayscale using the given weight precision.
+    Grayscale(Precision),
+    /// Reduce the stored image to a number of levels per channel with the given dithering.
+    Posterise(u16, Dither),
+    /// Blur the stored image by averaging every pixel with the pixels within the given radius.
+    Blur(u16),
+    /// Apply a position-dependent effect to the stored image using the given weight precision.
+    Overlay(Overlay, Precision),
+    /// Limit the operations that keep the size and colour type of the stored image to where a
+    /// single-channel mask is set. `None` clears the selection.
+    Selection(Option<EncryptedImage>),
+    /// Run a chain of operations on the stored image, answering only with the final result.
+    Pipeline(Vec<Operation>),
+    /// List the names of all slots holding an image.
+    ListSlots,
+    /// The names of all slots holding an image.
+    Slots(Vec<String>),
+    /// Delete the stored image.
+    Delete,
+    /// The result of an in-place operation was stored.
+    Stored,
+    /// Download the stored image.
+    Fetch,
+    /// The ID of a started job.
+    JobStarted(u64),
+    /// Ask how far a job has come, answered with its progress while it runs and with its result
+    /// once it has finished.
+    JobStatus(u64),
+    /// The progress of a running job.
+    Progress(ProgressReport),
+    /// Stop a job and discard its result.
+    Cancel(u64),
+    /// There is no such job, because it was cancelled, its result was already taken or it was
+    /// kept too long after finishing.
+    NoJob,
+    /// Compute the absolute difference between the stored image and the still image in the named
+    /// slot.
+    Difference(String),
+    /// Measure the difference between the stored image and the still image in the named slot.
+    Measure(Metric, String),
+    /// The result of a measurement.
+    Measurement(Measurement),
+    /// Score how well a template matches every position of the stored image, and whether to
+    /// locate the best match.
+    MatchTemplate(EncryptedImage, bool),
+    /// The result of template matching.
+    TemplateMatches(ScoreMap),
     /// There is no image stored on the server.
     NoImage,
+    /// The request was handled and has no result to send back.
+    Done,
+    /// The request failed.
+    Error {
+        code: ErrorCode,
+        description: String,
+    },
+}
+
+/// Samples in a message that are left out of it and sent in frames of rows after it.
+pub(crate) struct Rows<'a> {
+    pub samples: &'a mut Vec<EncryptedImageData>,
+    /// The number of samples in a row.
+    pub row_length: usize,
+    pub row_count: usize,
+}
+
+impl<'a> Rows<'a> {
+    fn of_image(image: &'a mut EncryptedImage) -> Self {
+        Self {
+            row_length: image.size.width as usize * image.channel_count() as usize,
+            row_count: image.size.height as usize,
+            samples: &mut image.data,
+        }
+    }
+
+    pub fn sample_count(&self) -> usize {
+        self.row_length * self.row_count
+    }
+}
+
+/// Why a request failed.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy)]
+pub enum ErrorCode {
+    /// The request could not be read or is not a request at all.
+    InvalidMessage,
+    /// The request cannot be applied to the stored image, like turning a grayscale image into
+    /// grayscale.
+    Unsupported,
+    /// The server failed while handling the request.
+    Internal,
+    /// The server cannot take the request right now, like when it holds as many sessions as it
+    /// can.
+    Busy,
+    /// The slot a result was to be stored in was changed by another request while the result was
+    /// computed, so it was not stored.
+    Conflict,
 }
 
 impl Message {
-    pub(crate) fn expect_answer(&self) -> bool {
+    /// Create an answer telling that a request failed.
+    pub fn error(code: ErrorCode, description: impl Into<String>) -> Self {
+        Message::Error {
+            code,
+            description: description.into(),
+        }
+    }
+
+    /// Whether the message is only ever sent by the server, in answer to a request.
+    pub(crate) fn is_answer(&self) -> bool {
+        matches!(
+            self,
+            Message::Pong
+                | Message::Session(_)
+                | Message::NoSession
+                | Message::Stored
+                | Message::Slots(_)
+                | Message::JobStarted(_)
+                | Message::Progress(_)
+                | Message::NoJob
+                | Message::Measurement(_)
+                | Message::TemplateMatches(_)
+                | Message::NoImage
+                | Message::Done
+                | Message::Error { .. }
+        )
+    }
+
+    /// Whether the message applies to the images of a client, so it needs to be sent in a session.
+    pub(crate) fn needs_session(&self) -> bool {
+        !matches!(
+            self,
+            Message::Ping
+                | Message::Pong
+                | Message::Shutdown
+                | Message::Register(_)
+                | Message::Resume(_, _)
+                | Message::Session(_)
+                | Message::NoSession
+        )
+    }
+}
+
+impl Payload for Message {
+    fn rows_mut(&mut self) -> Vec<Rows<'_>> {
         match self {
-            Message::Ping | Message::Rescale(_, _) | Message::Invert | Message::Grayscale => true,
-            Message::Pong | Message::Shutdown | Message::Image(_) | Message::NoImage => false,
+            Message::Image(image)
+            | Message::Selection(Some(image))
+            | Message::MatchTemplate(image, _) => vec![Rows::of_image(image)],
+            Message::Animation(animation) => animation
+                .frames
+                .iter_mut()
+                .map(|frame| Rows::of_image(&mut frame.image))
+                .collect(),
+            Message::TemplateMatches(matches) => vec![Rows {
+                row_length: matches.size.width as usize,
+                row_count: matches.size.height as usize,
+                samples: &mut matches.scores,
+            }],
+            _ => Vec::new(),
         }
     }
 }
+
+impl Request {
+    /// Create a request for a message on the default slot, answered right away.
+    pub fn new(message: Message) -> Self {
+        Self {
+            session: None,
+            slot: None,
+            in_place: false,
+            store_as: None,
+            job: false,
+            message,
+        }
+    }
+
+    pub fn with_slot(mut self, slot: Option<String>) -> Self {
+        self.slot = slot;
+        self
+    }
+
+    pub fn with_in_place(mut self, in_place: bool) -> Self {
+        self.in_place = in_place;
+        self
+    }
+
+    pub fn with_store_as(mut self, store_as: Option<String>) -> Self {
+        self.store_as = store_as;
+        self
+    }
+
+    pub fn in_background(mut self) -> Self {
+        self.job = true;
+        self
+    }
+}
+
+impl Payload for Request {
+    fn rows_mut(&mut self) -> Vec<Rows<'_>> {
+        self.message.rows_mut()
+    }
+}
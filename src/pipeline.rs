@@ -0,0 +1,197 @@
+use std::error::Error;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::crypt::Precision;
+use crate::image::dithering::Dither;
+use crate::image::overlay::Overlay;
+use crate::image::pixel_operations::Blend;
+use crate::image::rescaling::InterpolationType;
+use crate::image::Size;
+
+/// The names of all operations a textual pipeline can use.
+pub const OPERATION_NAMES: &[&str] = &[
+    "crop",
+    "rescale",
+    "invert",
+    "grayscale",
+    "posterise",
+    "blur",
+    "vignette",
+    "gradient",
+    "difference",
+];
+
+/// A step of a pipeline, turning an image into a new image.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub enum Operation {
+    /// Cut out the area of the given size from the given x and y coordinates.
+    Crop(u16, u16, Size),
+    /// Rescale to a new size using the given interpolation type and weight precision.
+    Rescale(Size, InterpolationType, Precision),
+    Invert,
+    /// Turn into grayscale using the given weight precision.
+    Grayscale(Precision),
+    /// Reduce to a number of levels per channel with the given dithering.
+    Posterise(u16, Dither),
+    /// Blur by averaging every pixel with the pixels within the given radius.
+    Blur(u16),
+    /// Apply a position-dependent effect using the given weight precision.
+    Overlay(Overlay, Precision),
+    /// Compute the absolute difference to the still image in the named slot.
+    Difference(String),
+}
+
+impl Operation {
+    /// Parse a step of a textual pipeline, with the name of the operation followed by its
+    /// comma-separated arguments after an equals sign, such as `blur=2` or `rescale=32x32,area`.
+    ///
+    /// The given weight precision is used by all operations that need one.
+    pub fn parse(step: &str, precision: Precision) -> Result<Self, Box<dyn Error>> {
+        let (name, arguments) = step.split_once('=').unwrap_or((step, ""));
+        let arguments = arguments
+            .split(',')
+            .filter(|argument| !argument.is_empty())
+            .collect::<Vec<_>>();
+        let argument = |index: usize| {
+            arguments
+                .get(index)
+                .copied()
+                .ok_or_else(|| format!("Missing argument {} of {}", index + 1, name))
+        };
+
+        Ok(match name {
+            "crop" => Operation::Crop(
+                argument(0)?.parse()?,
+                argument(1)?.parse()?,
+                parse_size(argument(2)?)?,
+            ),
+            "rescale" => Operation::Rescale(
+                parse_size(argument(0)?)?,
+                match arguments.get(1).copied().unwrap_or("nearest") {
+                    "nearest" => InterpolationType::Nearest,
+                    "bilinear" => InterpolationType::Bilinear,
+                    "area" => InterpolationType::Area,
+                    other => return Err(format!("Unknown interpolation type {}", other).into()),
+                },
+                precision,
+            ),
+            "invert" => Operation::Invert,
+            "grayscale" => Operation::Grayscale(precision),
+            "posterise" => Operation::Posterise(
+                argument(0)?.parse()?,
+                match arguments.get(1) {
+                    Some(dither) => Dither::from_str(dither, true)?,
+                    None => Dither::default(),
+                },
+            ),
+            "blur" => Operation::Blur(arguments.first().unwrap_or(&"1").parse()?),
+            "vignette" => Operation::Overlay(
+                Overlay::Vignette {
+                    strength: arguments.first().unwrap_or(&"0.5").parse()?,
+                },
+                precision,
+            ),
+            "gradient" => Operation::Overlay(
+                Overlay::Gradient {
+                    angle: argument(0)?.parse()?,
+                    from: argument(1)?.parse()?,
+                    to: argument(2)?.parse()?,
+                    blend: match arguments.get(3) {
+                        Some(blend) => Blend::from_str(blend, true)?,
+                        None => Blend::default(),
+                    },
+                },
+                precision,
+            ),
+            "difference" => Operation::Difference(argument(0)?.to_string()),
+            _ => return Err(format!("Unknown operation {}", name).into()),
+        })
+    }
+
+    /// Whether the operation is limited to the selection, if there is one.
+    pub fn is_selectable(&self) -> bool {
+        match self {
+            Operation::Invert
+            | Operation::Posterise(_, _)
+            | Operation::Blur(_)
+            | Operation::Overlay(_, _) => true,
+            Operation::Crop(_, _, _)
+            | Operation::Rescale(_, _, _)
+            | Operation::Grayscale(_)
+            | Operation::Difference(_) => false,
+        }
+    }
+}
+
+/// Parse a size given as width and height separated by an `x`, such as `32x32`.
+fn parse_size(size: &str) -> Result<Size, Box<dyn Error>> {
+    let (width, height) = size
+        .split_once('x')
+        .ok_or_else(|| format!("Invalid size {}", size))?;
+
+    Ok(Size {
+        width: width.parse()?,
+        height: height.parse()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(step: &str) -> Result<Operation, String> {
+        Operation::parse(step, Precision::new(8)).map_err(|error| error.to_string())
+    }
+
+    #[test]
+    fn parses_operations_with_defaults() {
+        assert_eq!(parse("invert"), Ok(Operation::Invert));
+        assert_eq!(parse("blur"), Ok(Operation::Blur(1)));
+        assert_eq!(
+            parse("rescale=32x16"),
+            Ok(Operation::Rescale(
+                Size {
+                    width: 32,
+                    height: 16
+                },
+                InterpolationType::Nearest,
+                Precision::new(8)
+            ))
+        );
+        assert_eq!(
+            parse("posterise=4,ordered"),
+            Ok(Operation::Posterise(4, Dither::Ordered))
+        );
+        assert_eq!(
+            parse("difference=other"),
+            Ok(Operation::Difference("other".to_string()))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_steps() {
+        assert_eq!(
+            parse("sharpen"),
+            Err("Unknown operation sharpen".to_string())
+        );
+        assert_eq!(
+            parse("crop=1,2"),
+            Err("Missing argument 3 of crop".to_string())
+        );
+        assert_eq!(
+            parse("difference"),
+            Err("Missing argument 1 of difference".to_string())
+        );
+        assert_eq!(
+            parse("rescale=32x32,cubic"),
+            Err("Unknown interpolation type cubic".to_string())
+        );
+        assert_eq!(parse("rescale=32"), Err("Invalid size 32".to_string()));
+        assert!(parse("rescale=32xlarge").is_err());
+        assert!(parse("blur=-1").is_err());
+        assert!(parse("posterise=4,noise").is_err());
+        assert!(parse("gradient=90,0.5,1,screen").is_err());
+    }
+}
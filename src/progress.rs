@@ -0,0 +1,200 @@
+use std::cell::{Cell, RefCell};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+thread_local! {
+    /// The progress of the job running on the current thread, if any.
+    static CURRENT: RefCell<Option<Arc<Progress>>> = const { RefCell::new(None) };
+    /// The steps the function run by [`scaled`] on the current thread counts as, if any.
+    static SCALE: Cell<Option<Scale>> = const { Cell::new(None) };
+}
+
+/// How far a job has come, shared between the thread running it and the connections asking about
+/// it.
+///
+/// Operations count their steps, roughly one per pixel, through [`expect`] and [`step`], which do
+/// nothing outside of a job. The total grows as the operations of a job start, so it is only
+/// complete once the last one is running.
+pub struct Progress {
+    done: AtomicU64,
+    total: AtomicU64,
+    cancelled: AtomicBool,
+    started: Instant,
+    /// When the tracked function returned, was cancelled or failed.
+    finished: OnceLock<Instant>,
+}
+
+/// A snapshot of the progress of a job.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy)]
+pub struct ProgressReport {
+    pub done: u64,
+    pub total: u64,
+    pub elapsed: Duration,
+    /// The estimated time until the steps known so far are done.
+    pub remaining: Option<Duration>,
+}
+
+/// The steps of a function counted as a different number of steps.
+#[derive(Clone, Copy)]
+struct Scale {
+    /// The number of steps the function counts as.
+    steps: u64,
+    /// The steps the function announced and took itself.
+    expected: u64,
+    done: u64,
+    /// The steps counted towards the job so far.
+    counted: u64,
+}
+
+/// The payload unwinding a cancelled job, which does not run the panic hook.
+struct Cancelled;
+
+impl Default for Progress {
+    fn default() -> Self {
+        Self {
+            done: AtomicU64::new(0),
+            total: AtomicU64::new(0),
+            cancelled: AtomicBool::new(false),
+            started: Instant::now(),
+            finished: OnceLock::new(),
+        }
+    }
+}
+
+impl Progress {
+    /// Run a function on the current thread with its steps counted by this progress.
+    ///
+    /// Returns `None` if the job is cancelled before the function finishes.
+    pub fn track<T>(self: &Arc<Self>, function: impl FnOnce() -> T) -> Option<T> {
+        if self.cancelled.load(Ordering::SeqCst) {
+            let _ = self.finished.set(Instant::now());
+            return None;
+        }
+
+        CURRENT.with(|current| *current.borrow_mut() = Some(self.clone()));
+        let result = panic::catch_unwind(AssertUnwindSafe(function));
+        CURRENT.with(|current| *current.borrow_mut() = None);
+        let _ = self.finished.set(Instant::now());
+
+        match result {
+            Ok(value) => Some(value),
+            Err(payload) if payload.is::<Cancelled>() => None,
+            Err(payload) => panic::resume_unwind(payload),
+        }
+    }
+
+    /// Stop the job at its next step.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// How long ago the job finished, or `None` while it is running.
+    pub fn finished_since(&self) -> Option<Duration> {
+        self.finished.get().map(Instant::elapsed)
+    }
+
+    pub fn report(&self) -> ProgressReport {
+        let (done, total) = (
+            self.done.load(Ordering::SeqCst),
+            self.total.load(Ordering::SeqCst),
+        );
+        let elapsed = self.started.elapsed();
+
+        ProgressReport {
+            done,
+            total,
+            elapsed,
+            remaining: (done > 0)
+                .then(|| elapsed.mul_f64(total.saturating_sub(done) as f64 / done as f64)),
+        }
+    }
+}
+
+/// Announce that the running operation takes the given number of steps.
+pub fn expect(steps: u64) {
+    if let Some(mut scale) = SCALE.get() {
+        scale.expected += steps;
+        SCALE.set(Some(scale));
+        return;
+    }
+
+    with_current(|progress| {
+        progress.total.fetch_add(steps, Ordering::SeqCst);
+    });
+}
+
+/// Count a step of the running operation, stopping it if its job was cancelled.
+pub fn step() {
+    with_current(|progress| {
+        if progress.cancelled.load(Ordering::SeqCst) {
+            panic::resume_unwind(Box::new(Cancelled));
+        }
+
+        let steps = match SCALE.get() {
+            Some(mut scale) => {
+                scale.done += 1;
+                let counted = (scale.steps * scale.done / scale.expected.max(1)).min(scale.steps);
+                let steps = counted.saturating_sub(scale.counted);
+                scale.counted = scale.counted.max(counted);
+                SCALE.set(Some(scale));
+
+                steps
+            }
+            None => 1,
+        };
+        progress.done.fetch_add(steps, Ordering::SeqCst);
+    });
+}
+
+/// Run a function on the current thread with all of its steps counting as the given number of
+/// steps, which the caller has to [`expect`] itself, like when part of its work is thrown away.
+///
+/// Functions run this way cannot be nested.
+pub fn scaled<T>(steps: u64, function: impl FnOnce() -> T) -> T {
+    SCALE.set(Some(Scale {
+        steps,
+        expected: 0,
+        done: 0,
+        counted: 0,
+    }));
+    let result = function();
+    let scale = SCALE.take();
+
+    // steps the function did not announce or take are counted at the end
+    with_current(|progress| {
+        if let Some(scale) = scale {
+            progress
+                .done
+                .fetch_add(scale.steps - scale.counted, Ordering::SeqCst);
+        }
+    });
+
+    result
+}
+
+/// The progress of the job running on the current thread, to count the steps of threads it starts.
+pub fn current() -> Option<Arc<Progress>> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+/// Run a function on a thread started by a job, counting its steps with the progress of the job
+/// taken by [`current`].
+///
+/// The function unwinds like the job itself when the job is cancelled.
+pub fn attach<T>(progress: Option<Arc<Progress>>, function: impl FnOnce() -> T) -> T {
+    CURRENT.with(|current| *current.borrow_mut() = progress);
+
+    function()
+}
+
+fn with_current(function: impl FnOnce(&Progress)) {
+    CURRENT.with(|current| {
+        if let Some(progress) = current.borrow().as_ref() {
+            function(progress);
+        }
+    });
+}
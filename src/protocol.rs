@@ -0,0 +1,434 @@
+use std::error::Error;
+use std::io::{Read, Write};
+use std::mem;
+
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::crypt::{EncryptedImageData, ParameterSet, Width, PARAMETER_SET};
+use crate::message::{Message, Request, Rows};
+use crate::pipeline::OPERATION_NAMES;
+
+/// The magic bytes at the start of every frame.
+pub const MAGIC_BYTES: [u8; 4] = *b"FHEP";
+/// The current version of the protocol, which has to match exactly between client and server.
+pub const VERSION: u16 = 2;
+/// The largest payload of a hello.
+///
+/// Every type of frame has its own limit, so foreign or broken traffic is rejected before
+/// allocating for it.
+pub const MAX_HELLO_LENGTH: u64 = 64 << 10;
+/// The largest payload of a request or answer, which holds no samples but may hold encrypted
+/// metadata.
+pub const MAX_MESSAGE_LENGTH: u64 = 64 << 20;
+/// The largest payload of a request registering a session, which holds a server key.
+pub const MAX_REGISTER_LENGTH: u64 = 256 << 20;
+/// The largest payload of a frame of rows.
+pub const MAX_ROWS_LENGTH: u64 = 32 << 20;
+/// An upper bound of the serialised size of a block of a ciphertext with [`PARAMETER_SET`].
+const MAX_BLOCK_LENGTH: u64 = 17 << 10;
+
+/// The header in front of every payload sent over a connection.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Header {
+    magic_bytes: [u8; 4],
+    pub version: u16,
+    pub frame_type: FrameType,
+    /// The length of the payload in bytes.
+    pub length: u64,
+}
+
+/// What the payload of a frame is.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy)]
+pub enum FrameType {
+    /// A [`Hello`], sent by the client and answered with the one of the server.
+    Hello,
+    /// A message sent by the client.
+    Request,
+    /// The message the server answers a request with.
+    Answer,
+    /// Samples of rows of an image in the message sent before, see [`write_message`].
+    ///
+    /// They keep every frame below its limit. Only the client encrypting an image while sending it
+    /// avoids holding it as a whole, the receiving side always does.
+    Rows,
+    /// A request registering a session, which is larger than other requests as it holds a server
+    /// key.
+    Register,
+}
+
+impl FrameType {
+    /// The type of frame to send a request in.
+    pub fn of_request(request: &Request) -> Self {
+        match request.message {
+            Message::Register(_) => FrameType::Register,
+            _ => FrameType::Request,
+        }
+    }
+
+    /// The largest payload of this type of frame.
+    pub fn max_payload_length(&self) -> u64 {
+        match self {
+            FrameType::Hello => MAX_HELLO_LENGTH,
+            FrameType::Request | FrameType::Answer => MAX_MESSAGE_LENGTH,
+            FrameType::Rows => MAX_ROWS_LENGTH,
+            FrameType::Register => MAX_REGISTER_LENGTH,
+        }
+    }
+}
+
+/// What a client or server supports, exchanged before using a server.
+///
+/// Its layout never changes, so both sides can tell each other their versions even when they do
+/// not match.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone)]
+pub struct Hello {
+    pub version: u16,
+    /// The parameter sets keys can be generated with.
+    pub parameter_sets: Vec<ParameterSet>,
+    /// The names of the operations pipelines can use.
+    pub operations: Vec<String>,
+    /// The largest payload of any frame.
+    pub max_payload_length: u64,
+}
+
+impl Hello {
+    /// Describe what this build supports.
+    pub fn current() -> Self {
+        Self {
+            version: VERSION,
+            parameter_sets: vec![PARAMETER_SET],
+            operations: OPERATION_NAMES
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+            max_payload_length: MAX_REGISTER_LENGTH,
+        }
+    }
+
+    /// Check whether this build can talk to the other side and use its keys with it.
+    pub fn check_compatible(&self) -> Result<(), Box<dyn Error>> {
+        check_version(self.version)?;
+        if !self.parameter_sets.contains(&PARAMETER_SET) {
+            return Err(format!("Parameter set {:?} is not supported", PARAMETER_SET).into());
+        }
+
+        Ok(())
+    }
+}
+
+/// Write a frame with the given payload.
+pub fn write_frame(
+    mut writer: impl Write,
+    frame_type: FrameType,
+    payload: &impl Serialize,
+) -> Result<(), Box<dyn Error>> {
+    let header = Header {
+        magic_bytes: MAGIC_BYTES,
+        version: VERSION,
+        frame_type,
+        length: options().serialized_size(payload)?,
+    };
+
+    options().serialize_into(&mut writer, &header)?;
+    options().serialize_into(&mut writer, payload)?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// A payload holding images or score maps whose samples are sent in frames of rows after it.
+pub(crate) trait Payload: Serialize {
+    /// The samples of the images and score maps in the payload.
+    fn rows_mut(&mut self) -> Vec<Rows<'_>>;
+}
+
+/// Write a message as a frame with the samples of its images and score maps left out, sending them
+/// in frames of rows afterwards, one after another.
+///
+/// This only keeps frames small, both sides still hold every image in the message as a whole.
+pub(crate) fn write_message(
+    mut writer: impl Write,
+    frame_type: FrameType,
+    mut message: impl Payload,
+) -> Result<(), Box<dyn Error>> {
+    let samples = message
+        .rows_mut()
+        .into_iter()
+        .map(|rows| (rows.row_length, mem::take(rows.samples)))
+        .collect::<Vec<_>>();
+
+    write_frame(&mut writer, frame_type, &message)?;
+    for (row_length, samples) in samples {
+        write_rows(&mut writer, &samples, row_length)?;
+    }
+
+    Ok(())
+}
+
+/// Write samples in frames of as many rows as fit a frame, or of parts of a row if not even one
+/// does.
+pub fn write_rows(
+    mut writer: impl Write,
+    samples: &[EncryptedImageData],
+    row_length: usize,
+) -> Result<(), Box<dyn Error>> {
+    let Some(first) = samples.first() else {
+        return Ok(());
+    };
+
+    for rows in samples.chunks(row_frame_length(Width::of(first), row_length)) {
+        write_frame(&mut writer, FrameType::Rows, &rows)?;
+    }
+
+    Ok(())
+}
+
+/// Read the frames of rows following a message written by [`write_message`] into it.
+///
+/// Rows are collected until the message is complete, so it is only handled once all of its samples
+/// arrived. Frames of rows are a chunked framing of large messages, not a stream operations could
+/// start on early.
+pub(crate) fn read_rows(
+    mut reader: impl Read,
+    message: &mut impl Payload,
+) -> Result<(), Box<dyn Error>> {
+    for rows in message.rows_mut() {
+        if !rows.samples.is_empty() {
+            return Err("Samples have to follow in frames of rows".into());
+        }
+
+        let sample_count = rows.sample_count();
+        let samples = rows.samples;
+        while samples.len() < sample_count {
+            let header = read_header(&mut reader)?;
+            check_version(header.version)?;
+            if header.frame_type != FrameType::Rows {
+                return Err(format!("Expected rows but received {:?}", header.frame_type).into());
+            }
+
+            let received: Vec<EncryptedImageData> = read_payload(&mut reader, &header)?;
+            if samples.len() + received.len() > sample_count {
+                return Err("Received more samples than were announced".into());
+            }
+            samples.extend(received);
+        }
+    }
+
+    Ok(())
+}
+
+/// Read a request after its header, along with the frames of rows following it.
+pub(crate) fn read_request(
+    mut reader: impl Read,
+    header: &Header,
+) -> Result<Request, Box<dyn Error>> {
+    check_version(header.version)?;
+    let mut request: Request = read_payload(&mut reader, header)?;
+    // only registering gets the larger limit of its frames
+    if FrameType::of_request(&request) != header.frame_type {
+        return Err(format!("Received a request in a {:?} frame", header.frame_type).into());
+    }
+
+    read_rows(&mut reader, &mut request)?;
+    Ok(request)
+}
+
+/// The number of samples of the given width in a frame of rows with the given number of samples
+/// each, which are whole rows unless a single row does not fit.
+pub fn row_frame_length(width: Width, row_length: usize) -> usize {
+    // the payload starts with the number of samples and every sample with its number of blocks
+    let sample_length = mem::size_of::<u64>() as u64 + width.num_blocks() as u64 * MAX_BLOCK_LENGTH;
+    let samples =
+        ((MAX_ROWS_LENGTH - mem::size_of::<u64>() as u64) / sample_length).max(1) as usize;
+
+    // rows without samples fit any frame
+    let row_length = row_length.max(1);
+    match samples / row_length {
+        0 => samples,
+        rows => rows * row_length,
+    }
+}
+
+/// Read the header of the next frame, failing if it is not a frame at all.
+pub fn read_header(mut reader: impl Read) -> Result<Header, Box<dyn Error>> {
+    let mut magic_bytes = [0; MAGIC_BYTES.len()];
+    reader.read_exact(&mut magic_bytes)?;
+
+    // the rest of the header is only read once it is known to be one
+    if magic_bytes != MAGIC_BYTES {
+        return Err("Not a frame of this protocol".into());
+    }
+
+    Ok(options().deserialize_from(magic_bytes.chain(reader))?)
+}
+
+/// Read the payload of a frame after its header, never reading past its announced length.
+///
+/// The version of the header is not checked, since only the caller knows if the payload is
+/// readable across versions.
+pub fn read_payload<T: DeserializeOwned>(
+    reader: impl Read,
+    header: &Header,
+) -> Result<T, Box<dyn Error>> {
+    if header.length > header.frame_type.max_payload_length() {
+        return Err(format!(
+            "Payload of {} bytes is too large for a {:?} frame",
+            header.length, header.frame_type
+        )
+        .into());
+    }
+
+    Ok(options()
+        .with_limit(header.length)
+        .deserialize_from(reader.take(header.length))?)
+}
+
+/// Check that the other side speaks the same protocol version.
+pub fn check_version(version: u16) -> Result<(), Box<dyn Error>> {
+    if version != VERSION {
+        return Err(format!(
+            "Unsupported protocol version {}, expected {}",
+            version, VERSION
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+fn options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::image::{BitDepth, ColorType, Image};
+
+    /// A frame with the given header fields in front of the payload.
+    fn frame(frame_type: FrameType, version: u16, payload: Vec<u8>) -> Vec<u8> {
+        let mut frame = options()
+            .serialize(&Header {
+                magic_bytes: MAGIC_BYTES,
+                version,
+                frame_type,
+                length: payload.len() as u64,
+            })
+            .unwrap();
+        frame.extend(payload);
+
+        frame
+    }
+
+    fn read(frame: Vec<u8>) -> Result<Request, Box<dyn Error>> {
+        let mut reader = Cursor::new(frame);
+        let header = read_header(&mut reader)?;
+        read_request(&mut reader, &header)
+    }
+
+    #[test]
+    fn reads_written_frames() {
+        let mut data = Vec::new();
+        write_message(&mut data, FrameType::Request, Request::new(Message::Ping)).unwrap();
+
+        let request = read(data).unwrap();
+        assert!(matches!(request.message, Message::Ping));
+    }
+
+    #[test]
+    fn rejects_foreign_traffic() {
+        assert!(read_header(&b"GET / HTTP/1.1\r\n\r\n"[..]).is_err());
+        assert!(read_header(&b"FH"[..]).is_err());
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let payload = options().serialize(&Request::new(Message::Ping)).unwrap();
+
+        assert!(read(frame(FrameType::Request, VERSION + 1, payload)).is_err());
+        assert!(check_version(VERSION).is_ok());
+        assert!(check_version(VERSION - 1).is_err());
+    }
+
+    #[test]
+    fn limits_payloads_by_frame_type() {
+        for frame_type in [
+            FrameType::Hello,
+            FrameType::Request,
+            FrameType::Rows,
+            FrameType::Register,
+        ] {
+            let header = Header {
+                magic_bytes: MAGIC_BYTES,
+                version: VERSION,
+                frame_type,
+                length: frame_type.max_payload_length() + 1,
+            };
+            assert!(read_payload::<Vec<u8>>(&[][..], &header).is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_requests_in_register_frames() {
+        let payload = options().serialize(&Request::new(Message::Ping)).unwrap();
+
+        let error = read(frame(FrameType::Register, VERSION, payload)).unwrap_err();
+        assert_eq!(error.to_string(), "Received a request in a Register frame");
+    }
+
+    #[test]
+    fn fits_whole_rows_into_frames() {
+        let sample_length = |width: Width| {
+            mem::size_of::<u64>() as u64 + width.num_blocks() as u64 * MAX_BLOCK_LENGTH
+        };
+
+        for width in [Width::EIGHT, Width::SIXTEEN] {
+            let samples = row_frame_length(width, 1);
+            assert!(samples as u64 * sample_length(width) + 8 <= MAX_ROWS_LENGTH);
+            assert!((samples as u64 + 1) * sample_length(width) + 8 > MAX_ROWS_LENGTH);
+
+            // frames hold as many whole rows as fit
+            let row_length = samples / 3 + 1;
+            assert_eq!(row_frame_length(width, row_length), 2 * row_length);
+            // or a part of a row if not even one does
+            assert_eq!(row_frame_length(width, samples + 1), samples);
+            assert_eq!(row_frame_length(width, 0), samples);
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_rows() {
+        let image = Image::new(Vec::new(), 2, 1, ColorType::Grayscale, BitDepth::Eight);
+        let payload = options()
+            .serialize(&Request::new(Message::Image(image)))
+            .unwrap();
+
+        // the samples never follow
+        let request = frame(FrameType::Request, VERSION, payload);
+        assert!(read(request.clone()).is_err());
+
+        // another frame follows instead of rows
+        let mut followed = request;
+        followed.extend(frame(FrameType::Request, VERSION, Vec::new()));
+        let error = read(followed).unwrap_err();
+        assert_eq!(error.to_string(), "Expected rows but received Request");
+    }
+
+    #[test]
+    fn deeply_nested_request_is_read_without_recursing() {
+        // a request followed by megabytes of variant tags, which used to nest messages in
+        // messages until the stack overflowed
+        let mut payload = options().serialize(&Request::new(Message::Ping)).unwrap();
+        let tag = payload.split_off(payload.len() - mem::size_of::<u32>());
+        payload.extend(tag.iter().copied().cycle().take(4 << 20));
+
+        let request = read(frame(FrameType::Request, VERSION, payload)).unwrap();
+        assert!(matches!(request.message, Message::Ping));
+    }
+}
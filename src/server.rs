@@ -1,112 +1,298 @@
+use std::collections::HashMap;
 use std::error::Error;
 use std::io::{BufReader, BufWriter};
-use std::net::{TcpListener, TcpStream};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use log::info;
+use log::{error, info};
 
+use crate::crypt::digest::Digest;
+use crate::crypt::key::{key_digest, SessionToken};
 use crate::crypt::ServerKeyType;
-use crate::image::pixel_operations::{grayscale, invert};
-use crate::image::rescaling::rescale;
-use crate::image::EncryptedImage;
-use crate::message::Message;
+use crate::message::{ErrorCode, Message, Request};
+use crate::protocol::{self, FrameType, Hello};
+use crate::server::jobs::JobQueue;
+use crate::server::session::Session;
 
+mod jobs;
+mod session;
+
+/// The most sessions a server holds at once, as every session keeps a server key in memory.
+///
+/// Registering another one drops the session used least recently.
+const MAX_SESSIONS: usize = 16;
+/// The most sessions registered from the same address, so a single client cannot push out the
+/// sessions of all others.
+const MAX_SESSIONS_PER_PEER: usize = 4;
+/// The most connections handled at once, each of which has its own thread.
+const MAX_CONNECTIONS: usize = 64;
+/// How long a connection may stay silent while its request is read.
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long a session is kept after the last request in it.
+const SESSION_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Default)]
 pub struct Server {
-    key: ServerKeyType,
-    image: Option<EncryptedImage>,
+    /// The sessions of all clients by their token.
+    sessions: Mutex<HashMap<SessionToken, Registration>>,
+    /// The workers running the jobs of all sessions.
+    jobs: Arc<JobQueue>,
+    /// Whether a client asked the server to shut down.
+    shutting_down: AtomicBool,
+    /// The number of connections being handled.
+    connections: AtomicUsize,
+}
+
+/// A session with what is needed to resume and expire it.
+struct Registration {
+    session: Arc<Session>,
+    /// The digest of the session's server key, which a client resuming the session has to match.
+    key_digest: Digest,
+    /// The address the session was registered from.
+    peer: IpAddr,
+    last_used: Instant,
 }
 
 impl Server {
-    /// Create a new server listening on the given address.
+    /// Create a new server without any sessions.
     ///
     /// # Examples
     ///
     /// ```
-    /// server::new(server_key).unwrap();
+    /// server::new();
     /// ```
-    pub fn new(key: ServerKeyType) -> Self {
-        Self { key, image: None }
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    /// Start a server listening on the given address.
+    /// Start a server listening on the given address, handling every connection on its own
+    /// thread.
+    ///
+    /// Failing connections are logged and do not stop the server. Connections beyond
+    /// [`MAX_CONNECTIONS`] are answered as busy and closed.
     ///
     /// # Examples
     ///
     /// ```
     /// server::start("127.0.0.1:34347").unwrap();
     /// ```
-    pub fn start(&mut self, address: &str) -> Result<(), Box<dyn Error>> {
+    pub fn start(&self, address: &str) -> Result<(), Box<dyn Error>> {
         let listener = TcpListener::bind(address)?;
+        let local_address = listener.local_addr()?;
         info!("Server listening on {}", address);
 
-        for stream in listener.incoming() {
-            let stream = stream?;
-            let reader = BufReader::new(&stream);
-            let message: Message = bincode::deserialize_from(reader)?;
-            let mut response = None;
-            info!("Received {:?}", message);
-
-            if !match message {
-                Message::Rescale(_, _) | Message::Invert | Message::Grayscale => {
-                    self.check_image(&stream)?
+        // running connections are finished before shutting down
+        thread::scope(|scope| -> Result<(), Box<dyn Error>> {
+            for stream in listener.incoming() {
+                if self.shutting_down.load(Ordering::SeqCst) {
+                    break;
                 }
-                _ => true,
-            } {
-                continue;
-            }
 
-            match message {
-                Message::Ping => self.send_message(Message::Pong, &stream)?,
-                Message::Shutdown => break,
-                Message::Image(image) => self.image = Some(image),
-                Message::Rescale(size, interpolation_type) => {
-                    if let Some(image) = &self.image {
-                        response = Some(Message::Image(rescale(
-                            image,
-                            &self.key,
-                            size,
-                            interpolation_type,
-                        )));
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(error) => {
+                        error!("Could not accept connection: {}", error);
+                        continue;
                     }
+                };
+                if let Err(error) = stream.set_read_timeout(Some(READ_TIMEOUT)) {
+                    error!("Could not set read timeout: {}", error);
+                    continue;
                 }
-                Message::Invert => {
-                    if let Some(image) = &self.image {
-                        response = Some(Message::Image(invert(image, &self.key)));
+                if self.connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                    self.connections.fetch_sub(1, Ordering::SeqCst);
+                    let busy =
+                        Message::error(ErrorCode::Busy, "The server has too many connections");
+                    if let Err(error) = self.send_message(busy, &stream) {
+                        error!("Could not refuse connection: {}", error);
                     }
+                    continue;
                 }
-                Message::Grayscale => {
-                    if let Some(image) = &self.image {
-                        if let Some(grayscale_image) = grayscale(image, &self.key) {
-                            response = Some(Message::Image(grayscale_image));
-                        }
+
+                scope.spawn(move || {
+                    if let Err(error) = self.handle_connection(&stream, local_address) {
+                        error!("Connection failed: {}", error);
                     }
-                }
-                Message::Pong | Message::NoImage => {}
+                    self.connections.fetch_sub(1, Ordering::SeqCst);
+                });
             }
 
-            if let Some(response_message) = response {
-                self.send_message(response_message, &stream)?;
-            }
-        }
+            Ok(())
+        })?;
         info!("Shutting down");
 
         Ok(())
     }
 
-    fn send_message(&self, message: Message, stream: &TcpStream) -> Result<(), Box<dyn Error>> {
-        let writer = BufWriter::new(stream);
-        bincode::serialize_into(writer, &message)?;
+    fn handle_connection(
+        &self,
+        stream: &TcpStream,
+        local_address: SocketAddr,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut reader = BufReader::new(stream);
+        // traffic of other protocols is dropped without an answer
+        let header = protocol::read_header(&mut reader)?;
+        let request = match header.frame_type {
+            FrameType::Hello => {
+                let hello: Hello = protocol::read_payload(&mut reader, &header)?;
+                info!("Received {:?}", hello);
 
-        Ok(())
+                // the client decides whether it can use this server
+                return protocol::write_frame(
+                    BufWriter::new(stream),
+                    FrameType::Hello,
+                    &Hello::current(),
+                );
+            }
+            FrameType::Request | FrameType::Register => {
+                protocol::read_request(&mut reader, &header)
+            }
+            FrameType::Answer | FrameType::Rows => {
+                Err(format!("Expected a request but received {:?}", header.frame_type).into())
+            }
+        };
+        let request = match request {
+            Ok(request) => request,
+            Err(error) => {
+                // the client still gets an answer before the connection is dropped
+                self.send_message(
+                    Message::error(
+                        ErrorCode::InvalidMessage,
+                        format!("Could not read request: {}", error),
+                    ),
+                    stream,
+                )?;
+
+                return Err(error);
+            }
+        };
+        info!("Received {:?}", request);
+
+        let response = match request.message {
+            Message::Ping => Message::Pong,
+            Message::Shutdown => {
+                self.shutting_down.store(true, Ordering::SeqCst);
+                // wake up the listener waiting for the next connection
+                match TcpStream::connect(local_address) {
+                    Ok(_) => Message::Done,
+                    Err(error) => Message::error(
+                        ErrorCode::Internal,
+                        format!("Could not stop listening: {}", error),
+                    ),
+                }
+            }
+            Message::Register(key) => match key_digest(&key.0) {
+                Ok(key_digest) => self.register(key.0, key_digest, stream.peer_addr()?.ip()),
+                Err(error) => Message::error(
+                    ErrorCode::InvalidMessage,
+                    format!("Could not read server key: {}", error),
+                ),
+            },
+            Message::Resume(token, key_digest) => match self.session(&token) {
+                // a session is only resumed with the key it was registered with
+                Some((_, session_key_digest)) if session_key_digest == key_digest => {
+                    Message::Session(token)
+                }
+                _ => Message::NoSession,
+            },
+            message if message.is_answer() => Message::error(
+                ErrorCode::InvalidMessage,
+                "Expected a request but received an answer",
+            ),
+            message => match request.session.as_ref().map(|token| self.session(token)) {
+                // a failing operation only fails its own request
+                Some(Some((session, _))) => {
+                    let request = Request { message, ..request };
+                    panic::catch_unwind(AssertUnwindSafe(|| session.handle(request)))
+                        .unwrap_or_else(|_| {
+                            error!("Request in a session failed");
+                            Message::error(ErrorCode::Internal, "The request failed on the server")
+                        })
+                }
+                Some(None) => {
+                    info!("Has no such session, informing client");
+                    Message::NoSession
+                }
+                None => {
+                    info!("Received a message outside of a session, informing client");
+                    Message::NoSession
+                }
+            },
+        };
+
+        self.send_message(response, stream)
     }
 
-    fn check_image(&self, stream: &TcpStream) -> Result<bool, Box<dyn Error>> {
-        if self.image.is_none() {
-            info!("Has no image stored, informing client");
-            self.send_message(Message::NoImage, stream)?;
+    /// Register a new session under a new token, dropping sessions that timed out or the one used
+    /// least recently to make room.
+    fn register(&self, key: ServerKeyType, key_digest: Digest, peer: IpAddr) -> Message {
+        let mut sessions = self.sessions();
+        sessions.retain(|_, registration| registration.last_used.elapsed() < SESSION_TIMEOUT);
+        let peer_sessions = sessions
+            .values()
+            .filter(|registration| registration.peer == peer)
+            .count();
+        if peer_sessions >= MAX_SESSIONS_PER_PEER {
+            return Message::error(
+                ErrorCode::Busy,
+                format!(
+                    "The server already holds {} sessions of this client",
+                    MAX_SESSIONS_PER_PEER
+                ),
+            );
+        }
+        if sessions.len() >= MAX_SESSIONS {
+            let least_recently_used = sessions
+                .iter()
+                .min_by_key(|(_, registration)| registration.last_used)
+                .map(|(token, _)| *token);
+            if let Some(token) = least_recently_used {
+                info!("Dropping the session used least recently");
+                sessions.remove(&token);
+            }
+        }
+
+        info!("Registering a new session");
+        let token = SessionToken::generate();
+        sessions.insert(
+            token,
+            Registration {
+                session: Arc::new(Session::new(key, self.jobs.clone())),
+                key_digest,
+                peer,
+                last_used: Instant::now(),
+            },
+        );
 
-            return Ok(false);
+        Message::Session(token)
+    }
+
+    /// Look up a session and the digest of its key, marking it as used.
+    ///
+    /// The sessions stay unlocked while the session is used. A session that timed out is dropped
+    /// instead.
+    fn session(&self, token: &SessionToken) -> Option<(Arc<Session>, Digest)> {
+        let mut sessions = self.sessions();
+        let registration = sessions.get_mut(token)?;
+        if registration.last_used.elapsed() >= SESSION_TIMEOUT {
+            sessions.remove(token);
+            return None;
         }
 
-        Ok(true)
+        registration.last_used = Instant::now();
+
+        Some((registration.session.clone(), registration.key_digest))
+    }
+
+    fn sessions(&self) -> MutexGuard<'_, HashMap<SessionToken, Registration>> {
+        self.sessions.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn send_message(&self, message: Message, stream: &TcpStream) -> Result<(), Box<dyn Error>> {
+        protocol::write_message(BufWriter::new(stream), FrameType::Answer, message)
     }
 }
@@ -0,0 +1,82 @@
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+
+/// How many jobs run at once, as every job already uses all cores for tiled operations.
+const WORKERS: usize = 2;
+/// The most jobs waiting for a worker, beyond which new jobs are refused.
+const MAX_QUEUED_JOBS: usize = 32;
+
+type Task = Box<dyn FnOnce() + Send>;
+
+/// A fixed number of threads running the jobs of all sessions in the order they were started.
+pub struct JobQueue {
+    sender: SyncSender<Task>,
+}
+
+impl JobQueue {
+    pub fn new() -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<Task>(MAX_QUEUED_JOBS);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for _ in 0..WORKERS {
+            let receiver = receiver.clone();
+            thread::spawn(move || loop {
+                // the lock is released before the task runs
+                let task = receiver
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .recv();
+                match task {
+                    Ok(task) => task(),
+                    Err(_) => break,
+                }
+            });
+        }
+
+        Self { sender }
+    }
+
+    /// Queue a task, which must not panic, returning `false` if too many are waiting already.
+    pub fn push(&self, task: impl FnOnce() + Send + 'static) -> bool {
+        self.sender.try_send(Box::new(task)).is_ok()
+    }
+}
+
+impl Default for JobQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Barrier;
+
+    use super::*;
+
+    #[test]
+    fn refuses_tasks_once_the_queue_is_full() {
+        let queue = JobQueue::new();
+        let (started, release) = (
+            Arc::new(Barrier::new(WORKERS + 1)),
+            Arc::new(Barrier::new(WORKERS + 1)),
+        );
+        for _ in 0..WORKERS {
+            let (started, release) = (started.clone(), release.clone());
+            assert!(queue.push(move || {
+                started.wait();
+                release.wait();
+            }));
+        }
+        // every worker is busy, so the next tasks wait
+        started.wait();
+
+        for _ in 0..MAX_QUEUED_JOBS {
+            assert!(queue.push(|| {}));
+        }
+        assert!(!queue.push(|| {}));
+
+        release.wait();
+    }
+}
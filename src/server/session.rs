@@ -0,0 +1,613 @@
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+use log::{error, info};
+
+use crate::crypt::ServerKeyType;
+use crate::image::animation::EncryptedAnimation;
+use crate::image::comparison::{difference, match_template, measure};
+use crate::image::dithering::{posterise, Dither};
+use crate::image::overlay::overlay;
+use crate::image::pixel_operations::{apply_mask, box_blur, grayscale, invert};
+use crate::image::rescaling::{crop, rescale};
+use crate::image::tiling::process_tiled;
+use crate::image::{EncryptedImage, EncryptedImageView};
+use crate::message::{ErrorCode, Message, Request};
+use crate::pipeline::Operation;
+use crate::progress::Progress;
+use crate::server::jobs::JobQueue;
+
+/// The slot messages apply to unless they name another one.
+pub const DEFAULT_SLOT: &str = "default";
+/// How long the result of a finished job is kept for its client to take it.
+const JOB_RETENTION: Duration = Duration::from_secs(60 * 60);
+
+/// The server key and images of a client.
+pub struct Session {
+    key: ServerKeyType,
+    jobs: Arc<JobQueue>,
+    /// The images of the session, only locked to look them up or replace them, so long operations
+    /// do not block other connections.
+    store: Mutex<Store>,
+}
+
+#[derive(Default)]
+struct Store {
+    /// The stored images by slot name, with still images kept as a single frame.
+    images: HashMap<String, Arc<EncryptedAnimation>>,
+    /// The masks limiting where operations on the image in a slot apply, by slot name.
+    selections: HashMap<String, Arc<EncryptedImage>>,
+    /// The jobs whose results were not taken yet, by ID.
+    jobs: HashMap<u64, Job>,
+    next_job_id: u64,
+}
+
+impl Store {
+    /// Drop finished jobs whose results were not taken in time.
+    fn expire_jobs(&mut self) {
+        self.jobs.retain(|_, job| {
+            job.progress
+                .finished_since()
+                .is_none_or(|finished_since| finished_since < JOB_RETENTION)
+        });
+    }
+}
+
+/// A message handled in the background.
+struct Job {
+    progress: Arc<Progress>,
+    /// The answer to the message, or `None` if the job was cancelled, sent once the job finished.
+    result: Receiver<thread::Result<Option<Message>>>,
+}
+
+/// The images besides the processed one that operations use, taken from the store when a request
+/// starts.
+struct Inputs {
+    selection: Option<Arc<EncryptedImage>>,
+}
+
+impl Session {
+    pub fn new(key: ServerKeyType, jobs: Arc<JobQueue>) -> Self {
+        Self {
+            key,
+            jobs,
+            store: Mutex::new(Store::default()),
+        }
+    }
+
+    /// Handle a request sent in this session, returning its answer.
+    pub fn handle(self: &Arc<Self>, request: Request) -> Message {
+        if let Some(error) = check_targets(&request) {
+            return error;
+        }
+        if request.job {
+            let request = Request {
+                job: false,
+                ..request
+            };
+            return match self.start_job(request) {
+                Some(id) => Message::JobStarted(id),
+                None => Message::error(ErrorCode::Busy, "The server has too many jobs waiting"),
+            };
+        }
+        match request.message {
+            Message::JobStatus(id) => return self.job_status(id),
+            Message::Cancel(id) => return self.cancel_job(id),
+            _ => {}
+        }
+
+        let Request {
+            slot,
+            in_place,
+            store_as,
+            message,
+            ..
+        } = request;
+        let slot = slot.unwrap_or_else(|| DEFAULT_SLOT.to_string());
+        let destination = store_as.or_else(|| in_place.then(|| slot.clone()));
+        if message.is_answer() {
+            return Message::error(
+                ErrorCode::InvalidMessage,
+                "Expected a request but received an answer",
+            );
+        }
+        if let Some(error) = check_overlays(&message) {
+            return error;
+        }
+        let mut response = Message::Done;
+        // what the destination holds before the request, so results computed from an image that
+        // was replaced in the meantime do not overwrite the newer one
+        let previous = destination
+            .as_ref()
+            .map(|destination| self.image(destination));
+
+        if !match message {
+            Message::Rescale(_, _, _)
+            | Message::Invert
+            | Message::Grayscale(_)
+            | Message::Posterise(_, _)
+            | Message::Blur(_)
+            | Message::Overlay(_, _)
+            | Message::MatchTemplate(_, _)
+            | Message::Fetch => self.check_image(&slot),
+            Message::Difference(ref other) | Message::Measure(_, ref other) => {
+                self.check_image(&slot) && self.check_image(other)
+            }
+            Message::Pipeline(ref operations) => {
+                self.check_image(&slot)
+                    && operations.iter().all(|operation| match operation {
+                        Operation::Difference(other) => self.check_image(other),
+                        _ => true,
+                    })
+            }
+            _ => true,
+        } {
+            return Message::NoImage;
+        }
+
+        match message {
+            Message::Image(image) => self.insert(slot, image.into()),
+            Message::Animation(animation) => self.insert(slot, animation),
+            Message::Delete => {
+                let mut store = self.store();
+                store.images.remove(&slot);
+                store.selections.remove(&slot);
+            }
+            Message::ListSlots => {
+                let mut names = self.store().images.keys().cloned().collect::<Vec<_>>();
+                names.sort();
+                response = Message::Slots(names);
+            }
+            Message::Selection(Some(mask)) => {
+                self.store().selections.insert(slot, Arc::new(mask));
+            }
+            Message::Selection(None) => {
+                self.store().selections.remove(&slot);
+            }
+            Message::Rescale(size, interpolation_type, precision) => {
+                response = self.run(
+                    &slot,
+                    &[Operation::Rescale(size, interpolation_type, precision)],
+                )
+            }
+            Message::Invert => response = self.run(&slot, &[Operation::Invert]),
+            Message::Grayscale(precision) => {
+                response = self.run(&slot, &[Operation::Grayscale(precision)])
+            }
+            Message::Posterise(levels, dither) => {
+                response = self.run(&slot, &[Operation::Posterise(levels, dither)])
+            }
+            Message::Blur(radius) => response = self.run(&slot, &[Operation::Blur(radius)]),
+            Message::Overlay(effect, precision) => {
+                response = self.run(&slot, &[Operation::Overlay(effect, precision)])
+            }
+            Message::Pipeline(operations) => response = self.run(&slot, &operations),
+            Message::Difference(other) => {
+                response = self.run(&slot, &[Operation::Difference(other)])
+            }
+            Message::Fetch => response = self.run(&slot, &[]),
+            Message::Measure(metric, other) => {
+                // measurements compare a single image
+                response = self
+                    .still_image(&slot)
+                    .zip(self.still_image(&other))
+                    .and_then(|(image, other)| {
+                        measure(
+                            &image.frames.first()?.image,
+                            &other.frames.first()?.image,
+                            metric,
+                            &self.key,
+                        )
+                    })
+                    .map_or_else(
+                        || {
+                            Message::error(
+                                ErrorCode::Unsupported,
+                                "Only still images of the same size and colour type can be \
+                                 measured against each other",
+                            )
+                        },
+                        Message::Measurement,
+                    )
+            }
+            Message::MatchTemplate(template, locate) => {
+                // templates are matched against a single image
+                response = self
+                    .still_image(&slot)
+                    .and_then(|image| {
+                        match_template(&image.frames.first()?.image, &template, locate, &self.key)
+                    })
+                    .map_or_else(
+                        || {
+                            Message::error(
+                                ErrorCode::Unsupported,
+                                "Templates can only be matched against a still image at least as \
+                                 large as the template, with the same colour type",
+                            )
+                        },
+                        Message::TemplateMatches,
+                    )
+            }
+            // handled by the server outside of sessions
+            Message::Ping | Message::Shutdown | Message::Register(_) | Message::Resume(_, _) => {
+                response = Message::error(
+                    ErrorCode::InvalidMessage,
+                    "The request cannot be sent in a session",
+                )
+            }
+            // handled before looking at the slots of the request
+            Message::JobStatus(_) | Message::Cancel(_) => {}
+            // rejected before handling the message
+            Message::Pong
+            | Message::Session(_)
+            | Message::NoSession
+            | Message::Stored
+            | Message::Slots(_)
+            | Message::JobStarted(_)
+            | Message::Progress(_)
+            | Message::NoJob
+            | Message::Measurement(_)
+            | Message::TemplateMatches(_)
+            | Message::NoImage
+            | Message::Done
+            | Message::Error { .. } => {}
+        }
+
+        if let Some((destination, previous)) = destination.zip(previous) {
+            response = match response {
+                Message::Image(image) => self.store_result(destination, previous, image.into()),
+                Message::Animation(animation) => {
+                    self.store_result(destination, previous, animation)
+                }
+                response => response,
+            };
+        }
+
+        response
+    }
+
+    /// Queue a request as a job, returning its ID or `None` if too many jobs are waiting.
+    fn start_job(self: &Arc<Self>, request: Request) -> Option<u64> {
+        let progress = Arc::new(Progress::default());
+        let (session, job_progress) = (self.clone(), progress.clone());
+        let (sender, result) = mpsc::channel();
+        // the store is locked until the job is known, so it is there once the job finishes
+        let mut store = self.store();
+        let queued = self.jobs.push(move || {
+            let answer = panic::catch_unwind(AssertUnwindSafe(|| {
+                job_progress.track(|| session.handle(request))
+            }));
+            let _ = sender.send(answer);
+        });
+        if !queued {
+            return None;
+        }
+
+        store.expire_jobs();
+        let id = store.next_job_id;
+        store.next_job_id += 1;
+        store.jobs.insert(id, Job { progress, result });
+        info!("Started job {}", id);
+
+        Some(id)
+    }
+
+    /// Report the progress of a job, or take its result if it has finished.
+    fn job_status(&self, id: u64) -> Message {
+        let answer = {
+            let mut store = self.store();
+            store.expire_jobs();
+            let Some(job) = store.jobs.get(&id) else {
+                return Message::NoJob;
+            };
+            let answer = match job.result.try_recv() {
+                Err(TryRecvError::Empty) => return Message::Progress(job.progress.report()),
+                answer => answer,
+            };
+            store.jobs.remove(&id);
+
+            answer
+        };
+
+        match answer {
+            Ok(Ok(Some(response))) => response,
+            Ok(Ok(None)) => Message::NoJob,
+            // the job failed, or its worker stopped without answering
+            Ok(Err(_)) | Err(_) => {
+                error!("Job {} failed", id);
+                Message::error(ErrorCode::Internal, format!("Job {} failed", id))
+            }
+        }
+    }
+
+    fn cancel_job(&self, id: u64) -> Message {
+        let mut store = self.store();
+        store.expire_jobs();
+        let Some(job) = store.jobs.remove(&id) else {
+            return Message::NoJob;
+        };
+
+        info!("Cancelling job {}", id);
+        // the job stops at its next step, or right away if it is still waiting for a worker
+        job.progress.cancel();
+        Message::Done
+    }
+
+    /// Run operations one after another on the image in a slot, answering with the final result.
+    fn run(&self, slot: &str, operations: &[Operation]) -> Message {
+        let (Some(image), inputs) = (self.image(slot), self.inputs(slot)) else {
+            return Message::NoImage;
+        };
+
+        let mut result = None;
+        for operation in operations {
+            let current = result.as_ref().unwrap_or(image.as_ref());
+            if let Some(error) = self.check_selection(current, operation, &inputs) {
+                return error;
+            }
+
+            info!("Applying {:?}", operation);
+            match self.apply(current, operation, &inputs) {
+                Some(processed) => result = Some(processed),
+                None => {
+                    return Message::error(
+                        ErrorCode::Unsupported,
+                        format!("{:?} cannot be applied to the image", operation),
+                    )
+                }
+            }
+        }
+
+        image_message(result.unwrap_or_else(|| image.as_ref().clone()))
+    }
+
+    fn apply(
+        &self,
+        image: &EncryptedAnimation,
+        operation: &Operation,
+        inputs: &Inputs,
+    ) -> Option<EncryptedAnimation> {
+        match operation {
+            Operation::Crop(x, y, size) => image.try_map_frames(|frame| crop(frame, *x, *y, *size)),
+            Operation::Rescale(size, interpolation_type, precision) => {
+                image.try_map_frames(|frame| {
+                    rescale(frame, &self.key, *size, *interpolation_type, *precision)
+                })
+            }
+            // operations on single pixels or small neighbourhoods run on tiles in parallel
+            Operation::Invert => image.try_map_frames(|frame| {
+                process_tiled(frame, 0, |tile| Some(invert(tile, &self.key)))
+                    .and_then(|inverted| self.selected(frame, inputs, inverted))
+            }),
+            Operation::Grayscale(precision) => image.try_map_frames(|frame| {
+                process_tiled(frame, 0, |tile| grayscale(tile, *precision, &self.key))
+            }),
+            Operation::Posterise(levels, dither) => image.try_map_frames(|frame| {
+                let posterise_tile =
+                    |tile: &EncryptedImageView| posterise(tile, *levels, *dither, &self.key);
+                // dithering depends on where pixels are and, with error diffusion, on all pixels
+                // before them
+                match dither {
+                    Dither::None => process_tiled(frame, 0, posterise_tile),
+                    Dither::Ordered | Dither::ErrorDiffusion => posterise_tile(&frame.view()),
+                }
+                .and_then(|posterised| self.selected(frame, inputs, posterised))
+            }),
+            Operation::Blur(radius) => image.try_map_frames(|frame| {
+                process_tiled(frame, *radius, |tile| box_blur(tile, *radius, &self.key))
+                    .and_then(|blurred| self.selected(frame, inputs, blurred))
+            }),
+            Operation::Overlay(effect, precision) => image.try_map_frames(|frame| {
+                self.selected(frame, inputs, overlay(frame, effect, *precision, &self.key))
+            }),
+            Operation::Difference(other) => {
+                let other = self.still_image(other)?;
+                let other = &other.frames.first()?.image;
+                image.try_map_frames(|frame| difference(frame, other, &self.key))
+            }
+        }
+    }
+
+    /// An error if an operation is limited to a selection that does not fit the image.
+    fn check_selection(
+        &self,
+        image: &EncryptedAnimation,
+        operation: &Operation,
+        inputs: &Inputs,
+    ) -> Option<Message> {
+        let mask = inputs
+            .selection
+            .as_ref()
+            .filter(|_| operation.is_selectable())?;
+        let size = image.frames.first()?.image.size;
+
+        (mask.size != size).then(|| {
+            Message::error(
+                ErrorCode::InvalidMessage,
+                format!(
+                    "The selection of {}x{} pixels does not fit the image of {}x{} pixels",
+                    mask.size.width, mask.size.height, size.width, size.height
+                ),
+            )
+        })
+    }
+
+    /// Limit a processed frame to the selection, if there is one. Returns `None` if it does not
+    /// fit the frame.
+    fn selected(
+        &self,
+        original: &EncryptedImage,
+        inputs: &Inputs,
+        processed: EncryptedImage,
+    ) -> Option<EncryptedImage> {
+        match inputs.selection.as_deref() {
+            Some(mask) => apply_mask(original, &processed, mask, &self.key),
+            None => Some(processed),
+        }
+    }
+
+    fn insert(&self, slot: String, image: EncryptedAnimation) {
+        self.store().images.insert(slot, Arc::new(image));
+    }
+
+    /// Store a result in a slot if the slot still holds the image it held before the request.
+    fn store_result(
+        &self,
+        slot: String,
+        previous: Option<Arc<EncryptedAnimation>>,
+        image: EncryptedAnimation,
+    ) -> Message {
+        let mut store = self.store();
+        let unchanged = match (store.images.get(&slot), &previous) {
+            (Some(current), Some(previous)) => Arc::ptr_eq(current, previous),
+            (current, previous) => current.is_none() && previous.is_none(),
+        };
+        if !unchanged {
+            return Message::error(
+                ErrorCode::Conflict,
+                format!(
+                    "The slot {} was changed by another request, so the result was not stored",
+                    slot
+                ),
+            );
+        }
+
+        store.images.insert(slot, Arc::new(image));
+        Message::Stored
+    }
+
+    fn image(&self, slot: &str) -> Option<Arc<EncryptedAnimation>> {
+        self.store().images.get(slot).cloned()
+    }
+
+    /// The image in a slot, if it is a still image.
+    fn still_image(&self, slot: &str) -> Option<Arc<EncryptedAnimation>> {
+        self.image(slot).filter(|image| !image.is_animated())
+    }
+
+    fn inputs(&self, slot: &str) -> Inputs {
+        Inputs {
+            selection: self.store().selections.get(slot).cloned(),
+        }
+    }
+
+    fn store(&self) -> MutexGuard<'_, Store> {
+        // the store stays consistent when a request panics, as it is only changed in single steps
+        self.store.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn check_image(&self, slot: &str) -> bool {
+        if !self.store().images.contains_key(slot) {
+            info!("Has no image stored in slot {}, informing client", slot);
+
+            return false;
+        }
+
+        true
+    }
+}
+
+/// Wrap a result in a message, sending still images as a single image.
+fn image_message(animation: EncryptedAnimation) -> Message {
+    if animation.is_animated() {
+        Message::Animation(animation)
+    } else {
+        match animation.into_first_frame() {
+            Some(image) => Message::Image(image),
+            None => Message::NoImage,
+        }
+    }
+}
+
+/// Reject a request whose result goes to more than one place, or which manages jobs but names a
+/// slot or a destination.
+fn check_targets(request: &Request) -> Option<Message> {
+    let targeted =
+        request.job || request.slot.is_some() || request.in_place || request.store_as.is_some();
+    let description = match request.message {
+        Message::JobStatus(_) | Message::Cancel(_) if targeted => {
+            "Jobs cannot be run on a slot, stored or run as a job"
+        }
+        _ if request.in_place && request.store_as.is_some() => {
+            "A result cannot be both stored in place and in another slot"
+        }
+        _ => return None,
+    };
+
+    Some(Message::error(ErrorCode::InvalidMessage, description))
+}
+
+/// An error if an overlay of a message has a weight map that does not match its size.
+fn check_overlays(message: &Message) -> Option<Message> {
+    let valid = match message {
+        Message::Overlay(effect, _) => effect.is_valid(),
+        Message::Pipeline(operations) => operations.iter().all(|operation| match operation {
+            Operation::Overlay(effect, _) => effect.is_valid(),
+            _ => true,
+        }),
+        _ => true,
+    };
+
+    (!valid).then(|| {
+        Message::error(
+            ErrorCode::InvalidMessage,
+            "The weight map does not have a weight for every pixel of its size",
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rejected(request: &Request) -> bool {
+        matches!(
+            check_targets(request),
+            Some(Message::Error {
+                code: ErrorCode::InvalidMessage,
+                ..
+            })
+        )
+    }
+
+    #[test]
+    fn accepts_one_destination() {
+        let slot = Some("other".to_string());
+        assert!(!rejected(&Request::new(Message::Invert)));
+        assert!(!rejected(
+            &Request::new(Message::Invert).with_in_place(true)
+        ));
+        assert!(!rejected(
+            &Request::new(Message::Invert)
+                .with_slot(slot.clone())
+                .with_store_as(slot)
+                .in_background()
+        ));
+    }
+
+    #[test]
+    fn rejects_storing_in_place_and_in_another_slot() {
+        let request = Request::new(Message::Invert)
+            .with_in_place(true)
+            .with_store_as(Some("other".to_string()));
+        assert!(rejected(&request));
+    }
+
+    #[test]
+    fn rejects_targets_of_managing_jobs() {
+        assert!(!rejected(&Request::new(Message::JobStatus(0))));
+        assert!(rejected(
+            &Request::new(Message::JobStatus(0)).in_background()
+        ));
+        assert!(rejected(
+            &Request::new(Message::Cancel(0)).with_slot(Some("other".to_string()))
+        ));
+        assert!(rejected(
+            &Request::new(Message::Cancel(0)).with_in_place(true)
+        ));
+    }
+}